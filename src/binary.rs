@@ -0,0 +1,176 @@
+//! Compact binary encoding of nets.
+//!
+//! Everything is written as LEB128 varints. Agents are stored as small
+//! integers chosen by the caller (for example `Program::agent_index`), and the
+//! interaction system isn't stored at all, so it has to be supplied again when
+//! decoding. Variables are renumbered densely in slotmap order.
+
+use std::{collections::BTreeMap, rc::Rc};
+
+use crate::run::{AgentId, InteractionSystem, Net, Tree, VarId};
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let Some(byte) = self.bytes.get(self.index) else {
+                return Err("Unexpected end of input".to_string());
+            };
+            self.index += 1;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err("Varint is too long".to_string())
+    }
+    fn usize(&mut self) -> Result<usize, String> {
+        self.varint()?
+            .try_into()
+            .map_err(|_| "Length does not fit in usize".to_string())
+    }
+    /// A number of items that each take at least one byte, so it can't be
+    /// larger than what is left of the input.
+    fn count(&mut self) -> Result<usize, String> {
+        let n = self.usize()?;
+        if n > self.bytes.len() - self.index {
+            return Err(format!("Count {} is larger than the rest of the input", n));
+        }
+        Ok(n)
+    }
+}
+
+const TAG_VAR: u64 = 0;
+const TAG_AGENT: u64 = 1;
+
+impl Net {
+    /// Encodes the pending interactions, stuck pairs and variable bindings.
+    /// Fails on variables that were never allocated in this net.
+    pub fn to_bytes(&self, agent_index: &dyn Fn(AgentId) -> u64) -> Result<Vec<u8>, String> {
+        fn write_tree(
+            out: &mut Vec<u8>,
+            tree: &Tree,
+            vars: &BTreeMap<VarId, u64>,
+            agent_index: &dyn Fn(AgentId) -> u64,
+        ) -> Result<(), String> {
+            match tree {
+                Tree::Var { id } => {
+                    let index = vars
+                        .get(id)
+                        .ok_or_else(|| format!("Dangling variable {}", tree))?;
+                    write_varint(out, TAG_VAR);
+                    write_varint(out, *index);
+                }
+                Tree::Agent { id, aux } => {
+                    write_varint(out, TAG_AGENT);
+                    write_varint(out, agent_index(*id));
+                    write_varint(out, aux.len() as u64);
+                    for x in aux {
+                        write_tree(out, x, vars, agent_index)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        let vars: BTreeMap<VarId, u64> = self
+            .vars
+            .keys()
+            .enumerate()
+            .map(|(i, id)| (id, i as u64))
+            .collect();
+        let mut out = vec![];
+        write_varint(&mut out, vars.len() as u64);
+        for (_, binding) in &self.vars {
+            match binding {
+                Some(tree) => {
+                    write_varint(&mut out, 1);
+                    write_tree(&mut out, tree, &vars, agent_index)?;
+                }
+                None => write_varint(&mut out, 0),
+            }
+        }
+        for pairs in [&self.interactions, &self.stuck] {
+            write_varint(&mut out, pairs.len() as u64);
+            for (a, b) in pairs {
+                write_tree(&mut out, a, &vars, agent_index)?;
+                write_tree(&mut out, b, &vars, agent_index)?;
+            }
+        }
+        Ok(out)
+    }
+    /// Decodes a net written by [`Net::to_bytes`], mapping agent indices
+    /// back with `agent` and attaching `system`.
+    pub fn from_bytes(
+        bytes: &[u8],
+        agent: &dyn Fn(u64) -> Option<AgentId>,
+        system: Rc<InteractionSystem>,
+    ) -> Result<Net, String> {
+        fn read_tree(
+            r: &mut Reader,
+            vars: &[VarId],
+            agent: &dyn Fn(u64) -> Option<AgentId>,
+        ) -> Result<Tree, String> {
+            match r.varint()? {
+                TAG_VAR => {
+                    let index = r.usize()?;
+                    let id = *vars
+                        .get(index)
+                        .ok_or_else(|| format!("Variable {} out of range", index))?;
+                    Ok(Tree::Var { id })
+                }
+                TAG_AGENT => {
+                    let index = r.varint()?;
+                    let id = agent(index).ok_or_else(|| format!("Unknown agent {}", index))?;
+                    let len = r.count()?;
+                    let aux = (0..len)
+                        .map(|_| read_tree(r, vars, agent))
+                        .collect::<Result<_, _>>()?;
+                    Ok(Tree::Agent { id, aux })
+                }
+                tag => Err(format!("Unknown tree tag {}", tag)),
+            }
+        }
+        let mut r = Reader { bytes, index: 0 };
+        let mut net = Net {
+            system,
+            ..Default::default()
+        };
+        let vars: Vec<VarId> = (0..r.count()?).map(|_| net.new_var()).collect();
+        for id in &vars {
+            if r.varint()? != 0 {
+                net.vars[*id] = Some(read_tree(&mut r, &vars, agent)?);
+            }
+        }
+        for _ in 0..r.count()? {
+            let a = read_tree(&mut r, &vars, agent)?;
+            let b = read_tree(&mut r, &vars, agent)?;
+            net.interactions.push((a, b));
+        }
+        for _ in 0..r.count()? {
+            let a = read_tree(&mut r, &vars, agent)?;
+            let b = read_tree(&mut r, &vars, agent)?;
+            net.stuck.push((a, b));
+        }
+        if r.index != bytes.len() {
+            return Err("Trailing bytes after net".to_string());
+        }
+        Ok(net)
+    }
+}
@@ -0,0 +1,219 @@
+//! Export of nets that are already made of interaction combinators.
+//!
+//! The caller picks which agents play the eraser, the constructor and the
+//! duplicator, and every rule between them has to be the corresponding
+//! combinator rule. Nets are written in an HVM-like notation, where `*` is
+//! the eraser, `(a b)` a constructor and `{a b}` a duplicator.
+//!
+//! Nothing is lowered: any other agent, including every type agent, makes
+//! the export fail. This is only the export half of compiling to
+//! combinators. Translating arbitrary agents and their rules into them, as
+//! in Lafont's proof that they are universal, is a separate piece of work
+//! that hasn't been done, so typed programs in general can't be exported.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    run::{var_namer, AgentId, InteractionSystem, Net, Tree, VarId},
+    Program,
+};
+
+/// The agents of a program that stand for each combinator.
+#[derive(Clone, Copy, Debug)]
+pub struct Alphabet {
+    pub eraser: AgentId,
+    pub constructor: AgentId,
+    pub duplicator: AgentId,
+}
+
+/// Ports of the rule between `a` and `b`, in that order, whichever way it was defined.
+fn rule_ports(system: &InteractionSystem, a: AgentId, b: AgentId) -> Option<(&[Tree], &[Tree])> {
+    if let Some(rule) = system.get_rule(a, b) {
+        Some((&rule.left_ports, &rule.right_ports))
+    } else {
+        let rule = system.get_rule(b, a)?;
+        Some((&rule.right_ports, &rule.left_ports))
+    }
+}
+
+fn var(tree: &Tree) -> Option<VarId> {
+    match tree {
+        Tree::Var { id } => Some(*id),
+        Tree::Agent { .. } => None,
+    }
+}
+
+/// The two variables under `tree` if it is `id(a b)`.
+fn binary(tree: &Tree, id: AgentId) -> Option<(VarId, VarId)> {
+    match tree {
+        Tree::Agent { id: x, aux } if *x == id && aux.len() == 2 => {
+            Some((var(&aux[0])?, var(&aux[1])?))
+        }
+        _ => None,
+    }
+}
+
+impl Alphabet {
+    /// Looks up the agents with the given names and checks that they behave
+    /// like combinators. Rules missing from the program are fine, since they
+    /// would never fire anyway.
+    pub fn from_names(
+        program: &Program,
+        eraser: &str,
+        constructor: &str,
+        duplicator: &str,
+    ) -> Result<Alphabet, String> {
+        let get = |name: &str| {
+            program
+                .agent_scope
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("Unknown agent {}", name))
+        };
+        let alphabet = Alphabet {
+            eraser: get(eraser)?,
+            constructor: get(constructor)?,
+            duplicator: get(duplicator)?,
+        };
+        let agents = [alphabet.eraser, alphabet.constructor, alphabet.duplicator];
+        for (i, a) in agents.iter().enumerate() {
+            for b in &agents[i..] {
+                if let Some((left, right)) = rule_ports(&program.system, *a, *b)
+                    && !alphabet.is_combinator_rule((*a, left), (*b, right))
+                {
+                    return Err(format!(
+                        "Rule {} ~ {} is not a combinator rule",
+                        program.lookup_agent(a).unwrap_or("?".to_string()),
+                        program.lookup_agent(b).unwrap_or("?".to_string())
+                    ));
+                }
+            }
+        }
+        Ok(alphabet)
+    }
+    fn is_combinator_rule(
+        &self,
+        (a, left): (AgentId, &[Tree]),
+        (b, right): (AgentId, &[Tree]),
+    ) -> bool {
+        let is_eraser =
+            |x: &Tree| matches!(x, Tree::Agent { id, aux } if *id == self.eraser && aux.is_empty());
+        if a == self.eraser || b == self.eraser {
+            // Erasure, or two erasers annihilating.
+            return left.iter().chain(right).all(is_eraser);
+        }
+        if a == b {
+            // Annihilation links the ports straight across.
+            let left: Option<Vec<VarId>> = left.iter().map(var).collect();
+            let right: Option<Vec<VarId>> = right.iter().map(var).collect();
+            return left.as_ref().is_some_and(|x| x.len() == 2 && x[0] != x[1]) && left == right;
+        }
+        // Commutation: `C(D(p q) D(r s)) ~ D(C(p r) C(q s))`.
+        let (con, dup) = if a == self.constructor {
+            (left, right)
+        } else {
+            (right, left)
+        };
+        let (Some((p, q)), Some((r, s))) = (
+            con.first().and_then(|x| binary(x, self.duplicator)),
+            con.get(1).and_then(|x| binary(x, self.duplicator)),
+        ) else {
+            return false;
+        };
+        let (Some(x), Some(y)) = (
+            dup.first().and_then(|x| binary(x, self.constructor)),
+            dup.get(1).and_then(|x| binary(x, self.constructor)),
+        ) else {
+            return false;
+        };
+        con.len() == 2 && dup.len() == 2 && x == (p, r) && y == (q, s)
+    }
+    /// Writes `tree` with bindings in `net` resolved. Fails with the first
+    /// agent that isn't part of the alphabet.
+    pub fn export_tree(
+        &self,
+        net: &Net,
+        show_var: &mut dyn FnMut(VarId) -> String,
+        tree: &Tree,
+    ) -> Result<String, AgentId> {
+        match net.substitute_ref(tree) {
+            Tree::Var { id } => Ok(show_var(id)),
+            Tree::Agent { id, aux } => {
+                let aux: Vec<String> = aux
+                    .iter()
+                    .map(|x| self.export_tree(net, show_var, x))
+                    .collect::<Result<_, _>>()?;
+                match aux.len() {
+                    0 if id == self.eraser => Ok("*".to_string()),
+                    2 if id == self.constructor => Ok(format!("({} {})", aux[0], aux[1])),
+                    2 if id == self.duplicator => Ok(format!("{{{} {}}}", aux[0], aux[1])),
+                    _ => Err(id),
+                }
+            }
+        }
+    }
+    /// Writes a net as `root & a ~ b & ...`. The root gathers the variables
+    /// that occur only once, nested in constructors, or is `*` if there are none.
+    pub fn export_net(&self, net: &Net) -> Result<String, AgentId> {
+        fn count(
+            net: &Net,
+            tree: &Tree,
+            counts: &mut BTreeMap<VarId, usize>,
+            order: &mut Vec<VarId>,
+        ) {
+            match net.substitute_ref(tree) {
+                Tree::Agent { aux, .. } => aux.iter().for_each(|x| count(net, x, counts, order)),
+                Tree::Var { id } => {
+                    let n = counts.entry(id).or_default();
+                    if *n == 0 {
+                        order.push(id);
+                    }
+                    *n += 1;
+                }
+            }
+        }
+        let pairs: Vec<&(Tree, Tree)> = net.interactions.iter().chain(net.stuck.iter()).collect();
+        let mut counts = BTreeMap::new();
+        let mut order = vec![];
+        for (a, b) in &pairs {
+            count(net, a, &mut counts, &mut order);
+            count(net, b, &mut counts, &mut order);
+        }
+        let mut show_var = var_namer();
+        let free: Vec<String> = order
+            .into_iter()
+            .filter(|x| counts[x] == 1)
+            .map(&mut show_var)
+            .collect();
+        let mut out = match free.split_last() {
+            None => "*".to_string(),
+            Some((last, rest)) => rest
+                .iter()
+                .rev()
+                .fold(last.clone(), |acc, x| format!("({} {})", x, acc)),
+        };
+        for (a, b) in pairs {
+            out.push_str(&format!(
+                " & {} ~ {}",
+                self.export_tree(net, &mut show_var, a)?,
+                self.export_tree(net, &mut show_var, b)?
+            ));
+        }
+        Ok(out)
+    }
+    /// Writes every check of `program` as a definition `@check_N = ...`.
+    pub fn export_checks(&self, program: &Program) -> Result<String, String> {
+        let mut out = String::new();
+        for (index, (_, net)) in program.checks.iter().enumerate() {
+            let net = self.export_net(net).map_err(|id| {
+                format!(
+                    "Agent {} in check {} is not one of the chosen combinators, and other agents can't be lowered",
+                    program.lookup_agent(&id).unwrap_or("?".to_string()),
+                    index
+                )
+            })?;
+            out.push_str(&format!("@check_{} = {}\n", index, net));
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,330 @@
+//! Hash-consing of trees.
+//!
+//! This is a prototype of a shared representation, where identical subtrees
+//! are stored once. [`Net::normal_interned`] reduces with it as an opt-in
+//! alternative to [`Net::normal`], converting the net to it and back, and
+//! [`Interner::intern_net`] measures how much sharing a net has. Since every
+//! variable occurs at most twice, mostly variable-free subtrees end up shared.
+
+use std::{
+    collections::BTreeMap,
+    rc::{Rc, Weak},
+};
+
+use crate::run::{AgentId, InteractionRule, Net, NetError, ReductionStrategy, Tree, VarId};
+
+/// An interned tree. Nodes made by the same [`Interner`] are equal exactly
+/// when they are the same allocation, so they can be compared with [`Rc::ptr_eq`].
+#[derive(Debug)]
+pub enum Node {
+    Agent { id: AgentId, aux: Vec<Rc<Node>> },
+    Var { id: VarId },
+}
+
+/// Children are identified by address, which is enough since they are
+/// interned themselves. A node keeps its children alive, so the entry of a
+/// live node never points to a freed child.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Agent(AgentId, Vec<*const Node>),
+    Var(VarId),
+}
+
+/// Entries don't keep their nodes alive, so nodes no tree uses anymore get
+/// freed, and their entries are dropped from time to time.
+#[derive(Debug, Default)]
+pub struct Interner {
+    nodes: BTreeMap<Key, Weak<Node>>,
+    /// Number of entries after dead ones were last dropped.
+    live: usize,
+}
+
+impl Interner {
+    /// The shared node for `tree`, creating it and its subtrees if needed.
+    pub fn intern(&mut self, tree: &Tree) -> Rc<Node> {
+        let (key, node) = match tree {
+            Tree::Agent { id, aux } => {
+                let aux: Vec<Rc<Node>> = aux.iter().map(|x| self.intern(x)).collect();
+                let key = Key::Agent(*id, aux.iter().map(Rc::as_ptr).collect());
+                (key, Node::Agent { id: *id, aux })
+            }
+            Tree::Var { id } => (Key::Var(*id), Node::Var { id: *id }),
+        };
+        self.node(key, node)
+    }
+    fn node(&mut self, key: Key, node: Node) -> Rc<Node> {
+        if let Some(node) = self.nodes.get(&key).and_then(Weak::upgrade) {
+            return node;
+        }
+        if self.nodes.len() > 2 * self.live.max(1024) {
+            self.nodes.retain(|_, x| x.strong_count() > 0);
+            self.live = self.nodes.len();
+        }
+        let node = Rc::new(node);
+        self.nodes.insert(key, Rc::downgrade(&node));
+        node
+    }
+    fn agent(&mut self, id: AgentId, aux: Vec<Rc<Node>>) -> Rc<Node> {
+        let key = Key::Agent(id, aux.iter().map(Rc::as_ptr).collect());
+        self.node(key, Node::Agent { id, aux })
+    }
+    fn var(&mut self, id: VarId) -> Rc<Node> {
+        self.node(Key::Var(id), Node::Var { id })
+    }
+    /// Interns every tree of the pending and stuck pairs, with bindings resolved.
+    pub fn intern_net(&mut self, net: &Net) -> Vec<(Rc<Node>, Rc<Node>)> {
+        net.interactions
+            .iter()
+            .chain(net.stuck.iter())
+            .map(|(a, b)| {
+                (
+                    self.intern(&net.substitute_ref(a)),
+                    self.intern(&net.substitute_ref(b)),
+                )
+            })
+            .collect()
+    }
+    /// Number of distinct nodes interned that are still in use.
+    pub fn len(&self) -> usize {
+        self.nodes.values().filter(|x| x.strong_count() > 0).count()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Node {
+    /// Copies the node back into an unshared tree.
+    pub fn to_tree(&self) -> Tree {
+        match self {
+            Node::Agent { id, aux } => Tree::Agent {
+                id: *id,
+                aux: aux.iter().map(|x| x.to_tree()).collect(),
+            },
+            Node::Var { id } => Tree::Var { id: *id },
+        }
+    }
+    /// Number of nodes in the tree, counting shared ones as many times as they occur.
+    pub fn size(&self) -> usize {
+        match self {
+            Node::Agent { aux, .. } => 1 + aux.iter().map(|x| x.size()).sum::<usize>(),
+            Node::Var { .. } => 1,
+        }
+    }
+}
+
+/// A rule with its trees interned, made the first time it is used.
+struct InternedRule {
+    left_ports: Vec<Rc<Node>>,
+    right_ports: Vec<Rc<Node>>,
+    body: Vec<(Rc<Node>, Rc<Node>)>,
+    /// Agents applying the rule creates, minus the two it consumes.
+    growth: isize,
+}
+
+/// State of [`Net::normal_interned`]. Variables are still allocated in
+/// `Net::vars`, but what they are bound to is kept here.
+#[derive(Default)]
+struct Reducer {
+    interner: Interner,
+    interactions: Vec<(Rc<Node>, Rc<Node>)>,
+    stuck: Vec<(Rc<Node>, Rc<Node>)>,
+    bindings: BTreeMap<VarId, Rc<Node>>,
+    rules: BTreeMap<(AgentId, AgentId), Option<Rc<InternedRule>>>,
+}
+
+impl Reducer {
+    fn rule(&mut self, net: &Net, a: AgentId, b: AgentId) -> Option<Rc<InternedRule>> {
+        if let Some(rule) = self.rules.get(&(a, b)) {
+            return rule.clone();
+        }
+        let rule = net.system.get_rule(a, b).map(|rule| {
+            let InteractionRule {
+                left_ports,
+                right_ports,
+                body,
+            } = rule;
+            let created = left_ports.iter().chain(right_ports).map(Tree::size);
+            let created = created.sum::<usize>()
+                + body.iter().map(|(a, b)| a.size() + b.size()).sum::<usize>();
+            Rc::new(InternedRule {
+                left_ports: left_ports.iter().map(|x| self.interner.intern(x)).collect(),
+                right_ports: right_ports
+                    .iter()
+                    .map(|x| self.interner.intern(x))
+                    .collect(),
+                body: body
+                    .iter()
+                    .map(|(a, b)| (self.interner.intern(a), self.interner.intern(b)))
+                    .collect(),
+                growth: created as isize - 2,
+            })
+        });
+        self.rules.insert((a, b), rule.clone());
+        rule
+    }
+    /// Like `Net::freshen`. Subtrees without variables are returned as they
+    /// are, so every instance of a rule shares them.
+    fn freshen(
+        &mut self,
+        net: &mut Net,
+        scope: &mut BTreeMap<VarId, VarId>,
+        node: &Rc<Node>,
+    ) -> Rc<Node> {
+        match &**node {
+            Node::Agent { id, aux } => {
+                let fresh: Vec<Rc<Node>> =
+                    aux.iter().map(|x| self.freshen(net, scope, x)).collect();
+                if fresh.iter().zip(aux).all(|(x, y)| Rc::ptr_eq(x, y)) {
+                    node.clone()
+                } else {
+                    self.interner.agent(*id, fresh)
+                }
+            }
+            Node::Var { id } => {
+                let id = match scope.remove(id) {
+                    Some(id) => id,
+                    None => {
+                        let fresh = net.new_var();
+                        scope.insert(*id, fresh);
+                        fresh
+                    }
+                };
+                self.interner.var(id)
+            }
+        }
+    }
+    /// Like `Net::interact_with_stuck`, with the same handling of frozen
+    /// agents, `pass_through` and `strict`.
+    fn interact(&mut self, net: &mut Net, a: Rc<Node>, b: Rc<Node>) -> Result<(), NetError> {
+        let (id1, aux1, id2, aux2) = match (&*a, &*b) {
+            (Node::Agent { id: id1, aux: aux1 }, Node::Agent { id: id2, aux: aux2 }) => {
+                (*id1, aux1, *id2, aux2)
+            }
+            (Node::Var { id }, _) | (_, Node::Var { id }) => {
+                let other = if matches!(&*a, Node::Var { id: x } if x == id) {
+                    b.clone()
+                } else {
+                    a.clone()
+                };
+                if let Some(bound) = self.bindings.remove(id) {
+                    net.vars.remove(*id);
+                    self.interactions.push((other, bound));
+                } else {
+                    self.bindings.insert(*id, other);
+                }
+                return Ok(());
+            }
+        };
+        let frozen = net.frozen.is_some_and(|x| x == id1 || x == id2);
+        let found = if frozen {
+            None
+        } else if let Some(rule) = self.rule(net, id1, id2) {
+            Some((rule, (id1, aux1), (id2, aux2)))
+        } else {
+            self.rule(net, id2, id1)
+                .map(|rule| (rule, (id2, aux2), (id1, aux1)))
+        };
+        let Some((rule, (left_id, left), (right_id, right))) = found else {
+            if !frozen && net.pass_through && aux1.len() == aux2.len() {
+                net.growth -= 2;
+                for (x, y) in aux1.iter().zip(aux2) {
+                    self.interactions.push((x.clone(), y.clone()));
+                }
+            } else if !frozen && net.strict {
+                return Err(NetError::Stuck(a.to_tree(), b.to_tree()));
+            } else {
+                self.stuck.push((a.clone(), b.clone()));
+            }
+            return Ok(());
+        };
+        for (agent, other, expected, found) in [
+            (left_id, right_id, rule.left_ports.len(), left.len()),
+            (right_id, left_id, rule.right_ports.len(), right.len()),
+        ] {
+            if expected != found {
+                return Err(NetError::ArityMismatch {
+                    agent,
+                    other,
+                    expected,
+                    found,
+                });
+            }
+        }
+        net.growth += rule.growth;
+        let mut scope = BTreeMap::new();
+        for (port, x) in rule
+            .left_ports
+            .iter()
+            .zip(left)
+            .chain(rule.right_ports.iter().zip(right))
+        {
+            let port = self.freshen(net, &mut scope, port);
+            self.interactions.push((port, x.clone()));
+        }
+        for (x, y) in &rule.body {
+            let x = self.freshen(net, &mut scope, x);
+            let y = self.freshen(net, &mut scope, y);
+            self.interactions.push((x, y));
+        }
+        Ok(())
+    }
+}
+
+impl Net {
+    /// Reduces the net like [`Net::normal`], but with trees stored as
+    /// [`Node`]s of a single [`Interner`] while reducing. Aux ports are
+    /// passed around by reference instead of being moved out of their
+    /// agents, and subtrees of rules that have no variables are shared by
+    /// every instance of the rule. Pairs are reduced most recent first, so
+    /// only the eager strategy without `priorities` is supported, and this
+    /// panics otherwise, or while a step is being recorded. Agents are
+    /// counted as if nothing was shared, for `size_limit` and `growth`. The
+    /// net is converted back even if reduction fails.
+    pub fn normal_interned(&mut self) -> Result<(), NetError> {
+        assert!(
+            self.strategy == ReductionStrategy::Eager
+                && self.priorities.is_empty()
+                && self.recording.is_none(),
+            "normal_interned only reduces eagerly, without priorities or recording"
+        );
+        let start = self.size() as isize - self.growth;
+        let mut reducer = Reducer::default();
+        for (id, x) in self.vars.iter_mut() {
+            if let Some(x) = x.take() {
+                reducer.bindings.insert(id, reducer.interner.intern(&x));
+            }
+        }
+        // Held back by an earlier lazy reduction, so they come last.
+        let deferred = std::mem::take(&mut self.deferred).into_iter().rev();
+        for (a, b) in deferred.chain(std::mem::take(&mut self.interactions)) {
+            let pair = (reducer.interner.intern(&a), reducer.interner.intern(&b));
+            reducer.interactions.push(pair);
+        }
+        for (a, b) in std::mem::take(&mut self.stuck) {
+            let pair = (reducer.interner.intern(&a), reducer.interner.intern(&b));
+            reducer.stuck.push(pair);
+        }
+        let mut res = Ok(());
+        while let Some((a, b)) = reducer.interactions.pop() {
+            if let Some(limit) = self.size_limit
+                && start + self.growth > limit as isize
+            {
+                reducer.interactions.push((a, b));
+                res = Err(NetError::SizeLimit(limit));
+                break;
+            }
+            res = reducer.interact(self, a, b);
+            if res.is_err() {
+                break;
+            }
+        }
+        for (id, x) in &reducer.bindings {
+            self.vars[*id] = Some(x.to_tree());
+        }
+        let to_trees = |(a, b): &(Rc<Node>, Rc<Node>)| (a.to_tree(), b.to_tree());
+        self.interactions = reducer.interactions.iter().map(to_trees).collect();
+        self.stuck = reducer.stuck.iter().map(to_trees).collect();
+        res
+    }
+}
@@ -1,14 +1,23 @@
 #![feature(let_chains)]
 
+#[cfg(feature = "binary")]
+pub mod binary;
+pub mod combinators;
+pub mod intern;
 pub mod run;
 pub mod syntax;
 
-use std::{collections::BTreeMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use itertools::iproduct;
-use run::{AgentId, InteractionSystem, Net, Tree, VarId};
+use run::{AgentId, InteractionSystem, Net, NetError, Tree, VarId};
 use slotmap::{DefaultKey, SlotMap};
-use syntax::Statement;
+use syntax::{Span, Statement};
 
 use crate::{run::InteractionRule, syntax::CodeParser};
 
@@ -29,6 +38,25 @@ pub struct Definition {
     left: UntypedMatch,
     right: UntypedMatch,
     net: Net,
+    /// Id of the statement this came from; see [`ProgramBuilder::add_statement`].
+    statement: usize,
+}
+
+impl Definition {
+    /// Shows the rule as `left ~ right`, followed by its body pairs.
+    fn show(&self, show_agent: &dyn Fn(AgentId) -> String) -> String {
+        let mut show_var = run::var_namer();
+        let mut show = |tree: &Tree| self.net.show_tree(show_agent, &mut show_var, false, tree);
+        let mut s = format!(
+            "{} ~ {}",
+            show(&self.left.clone().into()),
+            show(&self.right.clone().into())
+        );
+        for (l, r) in &self.net.interactions {
+            s.push_str(&format!(" with {} ~ {}", show(l), show(r)));
+        }
+        s
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -37,17 +65,78 @@ pub struct Declaration {
     intermediate: Vec<Tree>,
     r#type: UntypedMatch,
     net: Net,
+    statement: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct AgentMeta {
+    pub name: String,
+    /// Arity given by an `agent Name/N` statement, if any.
+    pub arity: Option<usize>,
+}
+
+/// Interns agent names. Programs built against the same scope give the same
+/// name the same `AgentId`.
+#[derive(Clone, Debug, Default)]
+pub struct AgentScope {
+    pub names: BTreeMap<String, AgentId>,
+    pub agents: SlotMap<DefaultKey, AgentMeta>,
+}
+
+/// What a [`ProgramBuilder`] with a shared scope does with an agent name that
+/// another program already put in the scope.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateAgentPolicy {
+    /// Refuse the statement that uses the name.
+    #[default]
+    Error,
+    /// Use the same agent as the other program.
+    Merge,
+    /// Make a new agent with the same name, only visible to this program.
+    Shadow,
 }
 
+/// Builds a single [`Program`]. Variable ids are allocated from slotmaps owned
+/// by the builder. Agent ids come from an [`AgentScope`], which by default is
+/// also owned by the builder, so every program has its own id space and ids
+/// from two different programs must not be compared or mixed. Builders made
+/// with [`ProgramBuilder::with_shared_scope`] intern agents in a common scope
+/// instead, and their programs can share ids, as decided by
+/// [`ProgramBuilder::duplicate_agents`].
 #[derive(Clone, Debug, Default)]
-struct ProgramBuilder {
+pub struct ProgramBuilder {
     var_scope: BTreeMap<String, VarId>,
+    /// Agents used by this program. A subset of `scope` when it is shared.
     agent_scope: BTreeMap<String, AgentId>,
-    net: Net,
-    agents: SlotMap<DefaultKey, ()>,
+    scope: Rc<RefCell<AgentScope>>,
     declarations: Vec<Declaration>,
     definitions: Vec<Definition>,
     checks: Vec<(bool, Net)>,
+    /// Statement each check came from, in the same order as `checks`.
+    check_statements: Vec<usize>,
+    /// Name each check was given, in the same order as `checks`.
+    check_names: Vec<Option<String>>,
+    /// Statement currently being loaded, and the id the next one will get.
+    statement: usize,
+    next_statement: usize,
+    /// Arity each agent is used with in rules and declarations, along with
+    /// the statement and place of each use. Only the first one is compared
+    /// against, the others are kept for when it gets removed.
+    arity_uses: BTreeMap<String, Vec<(usize, usize, String)>>,
+    /// Warnings, along with the statement they came from.
+    warnings: Vec<(usize, String)>,
+    /// Only matters for builders made with [`ProgramBuilder::with_shared_scope`].
+    /// Refusing is the default, so programs only share agents they were
+    /// meant to. Internal agents and the prelude's are always merged.
+    pub duplicate_agents: DuplicateAgentPolicy,
+    /// Print the annotator rule of each declaration to stderr as it is made.
+    pub verbose: bool,
+    /// Source span of every agent occurrence, in the order they were
+    /// loaded, along with the statement it is in.
+    agent_spans: BTreeMap<AgentId, Vec<(usize, Span)>>,
+    /// Set while loading text other than the program's own source, like the
+    /// rules of internal agents or the prelude, whose spans mean nothing to users.
+    skip_spans: bool,
 }
 
 impl Into<Tree> for UntypedMatch {
@@ -66,120 +155,518 @@ impl Tree {
             Tree::Var { .. } => None,
         }
     }
+    fn collect_vars(&self, out: &mut BTreeSet<VarId>) {
+        match self {
+            Tree::Agent { aux, .. } => aux.iter().for_each(|x| x.collect_vars(out)),
+            Tree::Var { id } => {
+                out.insert(*id);
+            }
+        }
+    }
+    /// Gives each occurrence of `var` its own fresh variable, in order.
+    fn split_var(&mut self, var: VarId, net: &mut Net, out: &mut Vec<VarId>) {
+        match self {
+            Tree::Agent { aux, .. } => aux.iter_mut().for_each(|x| x.split_var(var, net, out)),
+            Tree::Var { id } => {
+                if *id == var {
+                    *id = net.new_var();
+                    out.push(*id);
+                }
+            }
+        }
+    }
+    fn collect_agents(&self, out: &mut BTreeSet<AgentId>) {
+        if let Tree::Agent { id, aux } = self {
+            out.insert(*id);
+            aux.iter().for_each(|x| x.collect_agents(out));
+        }
+    }
 }
 
 impl ProgramBuilder {
-    fn create_annotation_node(&mut self) {
-        self.load_book(
-            CodeParser::new("__ANN(a b) ~ __ANN(a b)")
-                .parse_book()
-                .unwrap(),
-        );
-    }
-    fn get_ann_id(&mut self) -> AgentId {
-        if let Some(a) = self.agent_scope.get("__ANN") {
-            *a
-        } else {
-            self.create_annotation_node();
-            *self.agent_scope.get("__ANN").unwrap()
+    pub fn with_shared_scope(scope: Rc<RefCell<AgentScope>>) -> Self {
+        ProgramBuilder {
+            scope,
+            ..Default::default()
         }
     }
-    fn get_annotator_id(&mut self) -> AgentId {
-        if let Some(a) = self.agent_scope.get("__ANNOTATOR") {
-            *a
-        } else {
-            self.load_book(
-                CodeParser::new("__ANNOTATOR(a) ~ __ANNOTATOR(a)")
-                    .parse_book()
-                    .unwrap(),
-            );
-            *self.agent_scope.get("__ANNOTATOR").unwrap()
+    fn agent_name(&self, id: AgentId) -> String {
+        self.scope.borrow().agents[id].name.clone()
+    }
+    /// Loads the identity rule of an internal agent the first time it's needed.
+    /// This can only fail if the program already uses the name with another arity.
+    fn get_internal_id(&mut self, name: &str, rule: &str) -> Result<AgentId, String> {
+        if let Some(a) = self.agent_scope.get(name) {
+            return Ok(*a);
         }
+        self.load_book_without_spans(CodeParser::new(rule).parse_book()?)
+            .map_err(|e| format!("While creating internal agent {}: {}", name, e))?;
+        self.agent_scope
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("Internal agent {} was not created", name))
+    }
+    fn get_ann_id(&mut self) -> Result<AgentId, String> {
+        self.get_internal_id("__ANN", "__ANN(a b) ~ __ANN(a b)")
+    }
+    fn get_annotator_id(&mut self) -> Result<AgentId, String> {
+        self.get_internal_id("__ANNOTATOR", "__ANNOTATOR(a) ~ __ANNOTATOR(a)")
+    }
+    fn get_dup_id(&mut self) -> Result<AgentId, String> {
+        self.get_internal_id("__DUP", "__DUP(a b) ~ __DUP(a b)")
+    }
+    fn get_neg_id(&mut self) -> Result<AgentId, String> {
+        self.get_internal_id("__NEG", "__NEG(a) ~ __NEG(a)")
     }
     fn get_agent_id(&mut self, name: String) -> AgentId {
-        *self
-            .agent_scope
-            .entry(name)
-            .or_insert_with(|| self.agents.insert(()))
-    }
-    fn get_var_id(&mut self, name: String) -> VarId {
-        *self
-            .var_scope
-            .entry(name)
-            .or_insert_with(|| self.net.vars.insert(None))
-    }
-    fn load_untyped_match(&mut self, tree: syntax::UntypedMatch) -> UntypedMatch {
+        if let Some(id) = self.agent_scope.get(&name) {
+            return *id;
+        }
+        let mut scope = self.scope.borrow_mut();
+        let AgentScope { names, agents } = &mut *scope;
+        let id = *names.entry(name.clone()).or_insert_with(|| {
+            agents.insert(AgentMeta {
+                name: name.clone(),
+                arity: None,
+            })
+        });
+        self.agent_scope.insert(name, id);
+        id
+    }
+    /// Applies `self.duplicate_agents` to the agents of `statement` that are
+    /// new to this program but already in the shared scope.
+    fn resolve_foreign_agents(&mut self, statement: &Statement) -> Result<(), String> {
+        // Internal rules and the prelude are the same for every program.
+        if self.skip_spans {
+            return Ok(());
+        }
+        let mut names = BTreeSet::new();
+        statement.for_each_agent(&mut |name, _| {
+            names.insert(name.to_string());
+        });
+        match statement {
+            Statement::Agent(name, _) => {
+                names.insert(name.clone());
+            }
+            // Checks can only use agents this program already has.
+            Statement::Check(..) => return Ok(()),
+            // Duals of type parameters become variables before loading.
+            Statement::Decl(.., params) => params.iter().for_each(|x| {
+                names.remove(&format!("!{}", x));
+            }),
+            _ => (),
+        }
+        for name in names {
+            if name.starts_with("__")
+                || self.agent_scope.contains_key(&name)
+                || !self.scope.borrow().names.contains_key(&name)
+            {
+                continue;
+            }
+            match self.duplicate_agents {
+                DuplicateAgentPolicy::Error => {
+                    return Err(format!(
+                        "Agent {} is already defined by another program in the same scope",
+                        name
+                    ));
+                }
+                DuplicateAgentPolicy::Merge => (),
+                DuplicateAgentPolicy::Shadow => {
+                    let id = self.scope.borrow_mut().agents.insert(AgentMeta {
+                        name: name.clone(),
+                        arity: None,
+                    });
+                    self.agent_scope.insert(name, id);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn record_span(&mut self, id: AgentId, span: Span) {
+        if !self.skip_spans {
+            let statement = self.statement;
+            self.agent_spans
+                .entry(id)
+                .or_default()
+                .push((statement, span));
+        }
+    }
+    fn get_var_id(&mut self, name: String, net: &mut Net) -> VarId {
+        *self.var_scope.entry(name).or_insert_with(|| net.new_var())
+    }
+    fn load_untyped_match(&mut self, tree: syntax::UntypedMatch, net: &mut Net) -> UntypedMatch {
+        let id = self.get_agent_id(tree.name);
+        self.record_span(id, tree.span);
         UntypedMatch {
-            id: self.get_agent_id(tree.name),
-            aux: tree.aux.into_iter().map(|t| self.load_tree(t)).collect(),
+            id,
+            aux: tree
+                .aux
+                .into_iter()
+                .map(|t| self.load_tree(t, net))
+                .collect(),
         }
     }
-    fn load_typed_match(&mut self, tree: syntax::TypedMatch) -> TypedMatch {
+    fn load_typed_match(&mut self, tree: syntax::TypedMatch, net: &mut Net) -> TypedMatch {
+        let id = self.get_agent_id(tree.name);
+        self.record_span(id, tree.span);
         TypedMatch {
-            id: self.get_agent_id(tree.name),
+            id,
             aux: tree
                 .aux
                 .into_iter()
-                .map(|(a, b, c)| (self.load_tree(a), self.load_tree(b), self.load_tree(c)))
+                .map(|(a, b, c)| {
+                    (
+                        self.load_tree(a, net),
+                        self.load_tree(b, net),
+                        self.load_tree(c, net),
+                    )
+                })
                 .collect(),
         }
     }
-    fn load_tree(&mut self, tree: syntax::Tree) -> Tree {
+    /// Lowers a syntax tree, allocating its variables in `net` and pushing
+    /// any `with` redexes it contains onto `net.interactions`.
+    fn load_tree(&mut self, tree: syntax::Tree, net: &mut Net) -> Tree {
         match tree {
-            syntax::Tree::Agent { name, aux } => Tree::Agent {
-                id: self.get_agent_id(name),
-                aux: aux.into_iter().map(|x| self.load_tree(x)).collect(),
-            },
-            syntax::Tree::Variable { name } => Tree::Var {
-                id: self.get_var_id(name),
+            syntax::Tree::Agent { name, aux, span } => {
+                let id = self.get_agent_id(name);
+                self.record_span(id, span);
+                Tree::Agent {
+                    id,
+                    aux: aux.into_iter().map(|x| self.load_tree(x, net)).collect(),
+                }
+            }
+            syntax::Tree::Variable { .. } | syntax::Tree::Port(_) => Tree::Var {
+                id: self.get_var_id(tree.var_name().unwrap(), net),
             },
             syntax::Tree::With { rest, redex } => {
-                let t0 = self.load_tree(redex.0);
-                let t1 = self.load_tree(redex.1);
-                self.net.interactions.push((t0, t1));
-                self.load_tree(*rest)
+                let t0 = self.load_tree(redex.0, net);
+                let t1 = self.load_tree(redex.1, net);
+                net.interactions.push((t0, t1));
+                self.load_tree(*rest, net)
             }
         }
     }
-    fn load_statement(&mut self, statement: Statement) {
+    fn check_arities(&self, statement: &Statement) -> Result<(), String> {
+        let mut res = Ok(());
+        statement.for_each_agent(&mut |name, arity| {
+            if let Some(expected) = self
+                .agent_scope
+                .get(name)
+                .and_then(|x| self.scope.borrow().agents[*x].arity)
+                && expected != arity
+                && res.is_ok()
+            {
+                res = Err(format!(
+                    "Agent {} was declared with arity {} but is used with {} ports",
+                    name, expected, arity
+                ));
+            }
+        });
+        res
+    }
+    /// Checks that rules and declarations agree on the arity of every agent.
+    fn check_arity_consistency(&mut self, statement: &Statement) -> Result<(), String> {
+        let place = match statement {
+            Statement::Decl(a, ..) => format!("the declaration of {}", a.name),
+            Statement::Def(a, b, _) => format!("the rule {} ~ {}", a.name, b.name),
+            Statement::Check(..) | Statement::Agent(..) => return Ok(()),
+        };
+        let mut res = Ok(());
+        let id = self.statement;
+        statement.for_each_agent(&mut |name, arity| {
+            let uses = self.arity_uses.entry(name.to_string()).or_default();
+            match uses.first() {
+                Some((_, expected, first)) if *expected != arity => {
+                    if res.is_ok() {
+                        res = Err(format!(
+                            "Agent {} is used with {} ports in {}, but with {} ports in {}",
+                            name, expected, first, arity, place
+                        ));
+                    }
+                }
+                _ if uses.iter().any(|x| x.0 == id) => (),
+                _ => uses.push((id, arity, place.clone())),
+            }
+        });
+        res
+    }
+    fn load_statement(&mut self, statement: Statement) -> Result<(), String> {
+        self.resolve_foreign_agents(&statement)?;
+        self.check_arities(&statement)?;
+        self.check_arity_consistency(&statement)?;
+        let mut net = Net::default();
         match statement {
-            Statement::Decl(a, vars, t) => {
+            Statement::Decl(mut a, mut vars, mut t, params) => {
+                // `!a` stands for the dual of the type parameter `a`, and is
+                // scoped like a variable of its own.
+                let mut type_vars = vec![];
+                for param in &params {
+                    let dual = format!("!{}", param);
+                    let var = syntax::Tree::Variable { name: dual.clone() };
+                    for (_, _, x) in a.aux.iter_mut() {
+                        *x = x.replace_agent(&dual, &var);
+                    }
+                    for x in vars.iter_mut().chain(t.aux.iter_mut()) {
+                        *x = x.replace_agent(&dual, &var);
+                    }
+                    type_vars.extend([param.clone(), dual]);
+                }
+                self.check_decl_params(&a, &vars, &t, &params)?;
+                self.check_decl_wiring(&a, &vars, &t, &type_vars)?;
+                // Have to be created before loading, since that clears the variable scope.
+                let param_ids = if params.is_empty() {
+                    None
+                } else {
+                    Some((self.get_dup_id()?, self.get_neg_id()?))
+                };
+                let mut agent = self.load_typed_match(a, &mut net);
+                let mut intermediate: Vec<Tree> = vars
+                    .into_iter()
+                    .map(|x| self.load_tree(x, &mut net))
+                    .collect();
+                let mut r#type = self.load_untyped_match(t, &mut net);
+                if let Some(ids) = param_ids {
+                    for param in &params {
+                        let trees = intermediate
+                            .iter_mut()
+                            .chain(r#type.aux.iter_mut())
+                            .chain(agent.aux.iter_mut().map(|x| &mut x.2))
+                            .collect();
+                        self.lower_type_param(param, ids, trees, &mut net);
+                    }
+                }
                 let decl = Declaration {
-                    agent: self.load_typed_match(a),
-                    intermediate: vars.into_iter().map(|x| self.load_tree(x)).collect(),
-                    r#type: self.load_untyped_match(t),
-                    // note: relies on execution order
-                    net: core::mem::take(&mut self.net),
+                    agent,
+                    intermediate,
+                    r#type,
+                    net,
+                    statement: self.statement,
                 };
-                self.add_decl_annotator_rule(&decl);
+                self.add_decl_annotator_rule(&decl)?;
                 self.declarations.push(decl);
             }
-            Statement::Def(a, b) => {
+            Statement::Def(mut a, mut b, bindings) => {
+                // Inline the bindings, last first, since each one may use the
+                // ones before it.
+                for (name, tree) in bindings.into_iter().rev() {
+                    let mut counts = BTreeMap::new();
+                    syntax::Tree::from(a.clone()).count_vars(&mut counts);
+                    syntax::Tree::from(b.clone()).count_vars(&mut counts);
+                    let uses = counts.get(&name).copied().unwrap_or(0);
+                    if uses != 1 {
+                        return Err(format!(
+                            "Variable {} bound in where clause of {} ~ {} is used {} times, expected 1",
+                            name, a.name, b.name, uses
+                        ));
+                    }
+                    a.aux = a.aux.iter().map(|x| x.replace_var(&name, &tree)).collect();
+                    b.aux = b.aux.iter().map(|x| x.replace_var(&name, &tree)).collect();
+                }
+                let left = self.load_untyped_match(a, &mut net);
+                let right = self.load_untyped_match(b, &mut net);
                 let def = Definition {
-                    left: self.load_untyped_match(a),
-                    right: self.load_untyped_match(b),
-                    // note: relies on execution order
-                    net: core::mem::take(&mut self.net),
+                    left,
+                    right,
+                    net,
+                    statement: self.statement,
                 };
                 self.definitions.push(def);
             }
-            Statement::Check(positive, syntax::Net { interactions }) => {
+            Statement::Check(positive, name, syntax::Net { interactions }) => {
+                // `--only` picks checks by name, so a name can't be reused.
+                if let Some(name) = &name
+                    && self.check_names.iter().flatten().any(|x| x == name)
+                {
+                    return Err(format!("There is already a check named {:?}", name));
+                }
+                // Checks don't introduce agents, so any name not seen so far is a typo.
+                for (a, b) in &interactions {
+                    for tree in [a, b] {
+                        let mut unknown = None;
+                        tree.visit(&mut |t| {
+                            if let syntax::Tree::Agent { name, .. } = t
+                                && unknown.is_none()
+                                && !self.agent_scope.contains_key(name)
+                            {
+                                unknown = Some(name.clone());
+                            }
+                        });
+                        if let Some(name) = unknown {
+                            return Err(format!("unknown agent {} in check", name));
+                        }
+                    }
+                }
+                let mut pairs = vec![];
                 for (a, b) in interactions.into_iter() {
-                    let a = self.load_tree(a);
-                    let b = self.load_tree(b);
-                    self.net.interactions.push((a, b))
+                    let a = self.load_tree(a, &mut net);
+                    let b = self.load_tree(b, &mut net);
+                    pairs.push((a, b))
+                }
+                // The check's own pairs come before the `with` redexes inside
+                // them, so `type_of_check` can tell which one it is about.
+                pairs.append(&mut net.interactions);
+                net.interactions = pairs;
+                self.checks.push((positive, net));
+                self.check_statements.push(self.statement);
+                self.check_names.push(name);
+            }
+            Statement::Agent(name, arity) => {
+                let id = self.get_agent_id(name.clone());
+                let old = self.scope.borrow_mut().agents[id].arity.replace(arity);
+                if let Some(old) = old
+                    && old != arity
+                {
+                    return Err(format!(
+                        "Agent {} was declared with arity {} and then with arity {}",
+                        name, old, arity
+                    ));
                 }
-                self.checks.push((positive, core::mem::take(&mut self.net)))
             }
         }
         self.var_scope.clear();
+        Ok(())
+    }
+    /// Gives every use of a type parameter in `trees` its own variable, and
+    /// wires the first one into copies for the others. Uses of its dual go
+    /// through the negation agent.
+    fn lower_type_param(
+        &self,
+        param: &str,
+        (dup_id, neg_id): (AgentId, AgentId),
+        mut trees: Vec<&mut Tree>,
+        net: &mut Net,
+    ) {
+        let mut split = |name: &str, uses: &mut Vec<VarId>| {
+            if let Some(var) = self.var_scope.get(name) {
+                trees.iter_mut().for_each(|x| x.split_var(*var, net, uses));
+            }
+        };
+        let mut uses = vec![];
+        let mut duals = vec![];
+        split(param, &mut uses);
+        split(&format!("!{}", param), &mut duals);
+        for id in duals {
+            let var = net.new_var();
+            let neg = Tree::Agent {
+                id: neg_id,
+                aux: vec![Tree::Var { id }],
+            };
+            net.interactions.push((Tree::Var { id: var }, neg));
+            uses.push(var);
+        }
+        if uses.len() < 2 {
+            return;
+        }
+        let mut copies = Tree::Var {
+            id: uses.pop().unwrap(),
+        };
+        while uses.len() > 1 {
+            let id = uses.pop().unwrap();
+            copies = Tree::Agent {
+                id: dup_id,
+                aux: vec![Tree::Var { id }, copies],
+            };
+        }
+        net.interactions.push((Tree::Var { id: uses[0] }, copies));
     }
-    fn add_decl_annotator_rule(&mut self, decl: &Declaration) {
+    /// Type parameters can only appear in the types of a declaration.
+    fn check_decl_params(
+        &self,
+        agent: &syntax::TypedMatch,
+        intermediate: &[syntax::Tree],
+        r#type: &syntax::UntypedMatch,
+        params: &[String],
+    ) -> Result<(), String> {
+        let mut ports = BTreeMap::new();
+        let mut types = BTreeMap::new();
+        for (from, to, r#type) in &agent.aux {
+            from.count_vars(&mut ports);
+            to.count_vars(&mut ports);
+            r#type.count_vars(&mut types);
+        }
+        for tree in intermediate.iter().chain(r#type.aux.iter()) {
+            tree.count_vars(&mut types);
+        }
+        for param in params {
+            let dual = format!("!{}", param);
+            let used = |counts: &BTreeMap<String, usize>| {
+                counts.contains_key(param) || counts.contains_key(&dual)
+            };
+            if used(&ports) {
+                return Err(format!(
+                    "Type parameter `{}` of {} is used as a port",
+                    param, agent.name
+                ));
+            }
+            if !used(&types) {
+                return Err(format!(
+                    "Type parameter `{}` of {} is not used",
+                    param, agent.name
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// Every variable bound by a typed port has to appear exactly twice
+    /// across the declaration, otherwise the annotator rule leaves a wire dangling.
+    /// Variables of intermediate types have to appear somewhere else too.
+    fn check_decl_wiring(
+        &self,
+        agent: &syntax::TypedMatch,
+        intermediate: &[syntax::Tree],
+        r#type: &syntax::UntypedMatch,
+        params: &[String],
+    ) -> Result<(), String> {
+        let mut counts = BTreeMap::new();
+        for (from, to, r#type) in &agent.aux {
+            from.count_vars(&mut counts);
+            to.count_vars(&mut counts);
+            r#type.count_vars(&mut counts);
+        }
+        let port_vars: Vec<String> = counts
+            .keys()
+            .filter(|x| !params.contains(x))
+            .cloned()
+            .collect();
+        for tree in intermediate.iter().chain(r#type.aux.iter()) {
+            tree.count_vars(&mut counts);
+        }
+        for var in port_vars {
+            let uses = counts[&var];
+            if uses != 2 {
+                return Err(format!(
+                    "In declaration of {}: variable `{}` is used {} times, expected 2",
+                    agent.name, var, uses
+                ));
+            }
+        }
+        // An intermediate type that shares no variable with the rest of the
+        // declaration can't constrain anything.
+        for (i, tree) in intermediate.iter().enumerate() {
+            let mut own = BTreeMap::new();
+            tree.count_vars(&mut own);
+            for (var, n) in own {
+                if counts[&var] == n && !params.contains(&var) {
+                    return Err(format!(
+                        "In declaration of {}: variable `{}` of intermediate type {} is not used anywhere else",
+                        agent.name,
+                        var,
+                        i + 1
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+    fn add_decl_annotator_rule(&mut self, decl: &Declaration) -> Result<(), String> {
+        let annotator_id = self.get_annotator_id()?;
+        let ann_id = self.get_ann_id()?;
         let def = Definition {
             left: UntypedMatch {
-                id: self.get_annotator_id(),
+                id: annotator_id,
                 aux: vec![Tree::Agent {
-                    id: self.get_ann_id(),
+                    id: ann_id,
                     aux: vec![
                         Tree::Agent {
                             id: decl.agent.id,
@@ -199,68 +686,429 @@ impl ProgramBuilder {
                     .aux
                     .iter()
                     .map(|x| Tree::Agent {
-                        id: self.get_ann_id(),
+                        id: ann_id,
                         aux: vec![x.0.clone(), x.2.clone()],
                     })
                     .collect(),
             },
             net: decl.net.clone(),
+            statement: decl.statement,
         };
+        if self.verbose {
+            eprintln!(
+                "annotator rule for {}: {}",
+                self.agent_name(decl.agent.id),
+                def.show(&|id| self.agent_name(id))
+            );
+        }
         self.definitions.push(def);
+        Ok(())
+    }
+    /// Loads erasers, duplicators, booleans and Peano numbers, along with the
+    /// `Type` and `Universe` agents they're typed with. See `src/prelude.itt`.
+    pub fn load_prelude(&mut self) -> Result<(), String> {
+        self.load_book_without_spans(CodeParser::new(PRELUDE).parse_book()?)
+            .map_err(|e| format!("While loading the prelude: {}", e))
+    }
+    fn load_book_without_spans(&mut self, book: Vec<Statement>) -> Result<(), String> {
+        let outer = std::mem::replace(&mut self.skip_spans, true);
+        let res = self.load_book(book);
+        self.skip_spans = outer;
+        res
     }
-    fn load_book(&mut self, book: Vec<Statement>) {
-        book.into_iter().for_each(|x| self.load_statement(x))
+    pub fn load_book(&mut self, book: Vec<Statement>) -> Result<(), String> {
+        book.into_iter()
+            .try_for_each(|x| self.add_statement(x).map(|_| ()))
+    }
+    /// Loads a statement and returns the id under which it can later be
+    /// replaced or removed.
+    pub fn add_statement(&mut self, statement: Statement) -> Result<usize, String> {
+        let id = self.next_statement;
+        self.next_statement += 1;
+        self.load_statement_as(id, statement)?;
+        Ok(id)
+    }
+    /// Drops the rules, declarations, checks and warnings that came from a
+    /// statement. Agent names and arities declared with `agent` stay in scope.
+    pub fn remove_statement(&mut self, id: usize) {
+        self.definitions.retain(|x| x.statement != id);
+        self.declarations.retain(|x| x.statement != id);
+        let kept: Vec<bool> = self.check_statements.iter().map(|x| *x != id).collect();
+        let mut keep = kept.iter();
+        self.checks.retain(|_| *keep.next().unwrap());
+        let mut keep = kept.iter();
+        self.check_names.retain(|_| *keep.next().unwrap());
+        self.check_statements.retain(|x| *x != id);
+        for spans in self.agent_spans.values_mut() {
+            spans.retain(|x| x.0 != id);
+        }
+        for uses in self.arity_uses.values_mut() {
+            uses.retain(|x| x.0 != id);
+        }
+        self.arity_uses.retain(|_, x| !x.is_empty());
+        self.warnings.retain(|x| x.0 != id);
+    }
+    /// Swaps a statement for another one, keeping its id. Whatever the new
+    /// statement produces goes after everything else, so check indices of
+    /// later statements may shift. If the new statement fails to load, the
+    /// old one is kept as it was.
+    pub fn replace_statement(&mut self, id: usize, statement: Statement) -> Result<(), String> {
+        let old = self.clone();
+        self.remove_statement(id);
+        let res = self.load_statement_as(id, statement);
+        if res.is_err() {
+            *self = old;
+        }
+        res
+    }
+    fn load_statement_as(&mut self, id: usize, statement: Statement) -> Result<(), String> {
+        // Internal agents may get loaded in the middle of a statement.
+        let outer = std::mem::replace(&mut self.statement, id);
+        let res = self.load_statement(statement);
+        self.statement = outer;
+        res
+    }
+    /// Warns about rules that recreate their own active pair in their body,
+    /// which makes them loop forever once reduced. A rule's ports only ever
+    /// connect to the surrounding net, so the body is the only place where
+    /// the pair can be reintroduced unconditionally.
+    fn warn_looping_rules(&mut self, internal: [AgentId; 2]) {
+        let mut warnings = vec![];
+        for def in &self.definitions {
+            if internal.contains(&def.left.id) || internal.contains(&def.right.id) {
+                continue;
+            }
+            let pair = BTreeSet::from([def.left.id, def.right.id]);
+            let loops = def.net.interactions.iter().any(|(a, b)| {
+                a.agent_id()
+                    .zip(b.agent_id())
+                    .is_some_and(|(a, b)| BTreeSet::from([a, b]) == pair)
+            });
+            if loops {
+                warnings.push(format!(
+                    "Rule {} ~ {} reintroduces its own active pair and may not terminate",
+                    self.agent_name(def.left.id),
+                    self.agent_name(def.right.id)
+                ));
+            }
+        }
+        let id = self.statement;
+        self.warnings.extend(warnings.into_iter().map(|x| (id, x)));
+    }
+    /// Warns about type agents that can't meet the type they are checked
+    /// against. Annotations are compared by connecting their types, so a
+    /// type `A` needs a rule with `!A` when that agent exists, and some rule
+    /// otherwise. Without it the typecheck gets stuck on the two types.
+    /// Agents that are their own type, like `Type`, are left alone.
+    fn warn_type_agents_without_rules(&mut self, internal: &[AgentId]) {
+        let mut types = BTreeSet::new();
+        for decl in &self.declarations {
+            types.insert(decl.r#type.id);
+            types.extend(decl.intermediate.iter().filter_map(Tree::agent_id));
+            types.extend(decl.agent.aux.iter().filter_map(|x| x.2.agent_id()));
+        }
+        for decl in &self.declarations {
+            if decl.r#type.id == decl.agent.id {
+                types.remove(&decl.agent.id);
+            }
+        }
+        let mut warnings = vec![];
+        for &id in &types {
+            let rules_with = |other: &dyn Fn(AgentId) -> bool| {
+                self.definitions.iter().any(|x| {
+                    x.left.id == id && other(x.right.id) || x.right.id == id && other(x.left.id)
+                })
+            };
+            let name = self.agent_name(id);
+            let dual = match name.strip_prefix('!') {
+                Some(name) => name.to_string(),
+                None => format!("!{}", name),
+            };
+            if let Some(dual_id) = self.agent_scope.get(&dual).copied() {
+                // Reported once per pair, under the positive name.
+                let reported = name.starts_with('!') && types.contains(&dual_id);
+                if !reported && !rules_with(&|x| x == dual_id) {
+                    warnings.push(format!(
+                        "Type {} has no rule with {}, so checks using it will get stuck",
+                        name, dual
+                    ));
+                }
+            } else if !rules_with(&|x| !internal.contains(&x)) {
+                warnings.push(format!(
+                    "Type {} has no interaction rules, so checks using it will get stuck",
+                    name
+                ));
+            }
+        }
+        let id = self.statement;
+        self.warnings.extend(warnings.into_iter().map(|x| (id, x)));
+    }
+    /// Lets the internal duplicator copy every agent that appears in the
+    /// types of a declaration, so that type parameters can be instantiated
+    /// with any of them. Agents named `A` and `!A` are each other's negation.
+    fn add_type_param_rules(&mut self, dup_id: AgentId, neg_id: AgentId) {
+        fn visit(tree: &Tree, out: &mut BTreeMap<AgentId, usize>) {
+            if let Tree::Agent { id, aux } = tree {
+                out.entry(*id).or_insert(aux.len());
+                aux.iter().for_each(|x| visit(x, out));
+            }
+        }
+        let mut types = BTreeMap::new();
+        for decl in &self.declarations {
+            decl.agent.aux.iter().for_each(|x| visit(&x.2, &mut types));
+            decl.intermediate.iter().for_each(|x| visit(x, &mut types));
+            visit(&decl.r#type.clone().into(), &mut types);
+        }
+        let defined = |defs: &[Definition], a: AgentId, b: AgentId| {
+            defs.iter()
+                .any(|x| x.left.id == a && x.right.id == b || x.left.id == b && x.right.id == a)
+        };
+        for (id, arity) in types {
+            let mut net = Net::default();
+            let a: Vec<VarId> = (0..arity).map(|_| net.new_var()).collect();
+            let b: Vec<VarId> = (0..arity).map(|_| net.new_var()).collect();
+            let copy = |id, vars: &[VarId]| Tree::Agent {
+                id,
+                aux: vars.iter().map(|x| Tree::Var { id: *x }).collect(),
+            };
+            let name = self.agent_name(id);
+            let dual = match name.strip_prefix('!') {
+                Some(name) => self.agent_scope.get(name),
+                None => self.agent_scope.get(&format!("!{}", name)),
+            };
+            if let Some(dual) = dual.copied()
+                && !defined(&self.definitions, neg_id, id)
+            {
+                self.definitions.push(Definition {
+                    left: UntypedMatch {
+                        id: neg_id,
+                        aux: vec![copy(dual, &a)],
+                    },
+                    right: UntypedMatch {
+                        id,
+                        aux: a.iter().map(|x| Tree::Var { id: *x }).collect(),
+                    },
+                    net: net.clone(),
+                    statement: self.statement,
+                });
+            }
+            if defined(&self.definitions, dup_id, id) {
+                continue;
+            }
+            self.definitions.push(Definition {
+                left: UntypedMatch {
+                    id: dup_id,
+                    aux: vec![copy(id, &a), copy(id, &b)],
+                },
+                right: UntypedMatch {
+                    id,
+                    aux: a
+                        .iter()
+                        .zip(&b)
+                        .map(|(a, b)| Tree::Agent {
+                            id: dup_id,
+                            aux: vec![Tree::Var { id: *a }, Tree::Var { id: *b }],
+                        })
+                        .collect(),
+                },
+                net,
+                statement: self.statement,
+            });
+        }
     }
-    fn build_interaction_system(&mut self) -> Rc<InteractionSystem> {
+    fn build_interaction_system(
+        &mut self,
+        internal: [AgentId; 2],
+    ) -> Result<Rc<InteractionSystem>, String> {
+        self.warn_looping_rules(internal);
         let mut isys = InteractionSystem::default();
         for i in self.definitions.iter() {
-            assert!(isys
-                .rules
-                .entry(i.left.id)
-                .or_default()
-                .insert(
-                    i.right.id,
-                    InteractionRule {
-                        left_ports: i.left.aux.clone(),
-                        right_ports: i.right.aux.clone(),
-                    }
-                )
-                .is_none());
-            assert!(i.net.interactions.is_empty());
+            let names = || (self.agent_name(i.left.id), self.agent_name(i.right.id));
+            let old = isys.rules.entry(i.left.id).or_default().insert(
+                i.right.id,
+                InteractionRule {
+                    left_ports: i.left.aux.clone(),
+                    right_ports: i.right.aux.clone(),
+                    body: i.net.interactions.clone(),
+                },
+            );
+            if old.is_some() {
+                let (a, b) = names();
+                return Err(format!("Rule {} ~ {} is defined more than once", a, b));
+            }
         }
-        Rc::new(isys)
+        Ok(Rc::new(isys))
     }
-    fn finish(mut self) -> Program {
-        let system = self.build_interaction_system();
-        let annotator_id = self.get_annotator_id();
-        let ann_id = self.get_ann_id();
+    pub fn finish(mut self) -> Result<Program, TypeError> {
+        let annotator_id = self.get_annotator_id().map_err(TypeError::Load)?;
+        let ann_id = self.get_ann_id().map_err(TypeError::Load)?;
+        // Only programs with type parameters need these.
+        let dup_id = self.agent_scope.get("__DUP").copied();
+        let neg_id = self.agent_scope.get("__NEG").copied();
+        if let (Some(dup_id), Some(neg_id)) = (dup_id, neg_id) {
+            self.add_type_param_rules(dup_id, neg_id);
+        }
+        let internal: Vec<AgentId> = [Some(ann_id), Some(annotator_id), dup_id, neg_id]
+            .into_iter()
+            .flatten()
+            .collect();
+        self.warn_type_agents_without_rules(&internal);
+        let system = self
+            .build_interaction_system([ann_id, annotator_id])
+            .map_err(TypeError::Load)?;
 
-        Program {
+        Ok(Program {
             system,
             agent_scope: self.agent_scope,
-            agents: self.agents,
+            agents: self.scope.borrow().agents.clone(),
             declarations: self.declarations,
             definitions: self.definitions,
             checks: self.checks,
+            check_statements: self.check_statements,
+            check_names: self.check_names,
+            warnings: self.warnings.into_iter().map(|x| x.1).collect(),
+            agent_spans: self
+                .agent_spans
+                .into_iter()
+                .map(|(id, spans)| (id, spans.into_iter().map(|x| x.1).collect()))
+                .collect(),
+            strict: false,
+            interned: false,
+            step_limit: DEFAULT_STEP_LIMIT,
+            size_limit: None,
+            var_limit: None,
             annotator_id,
             ann_id,
+            dup_id,
+            neg_id,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TypeError {
+    Io(String),
+    Parse(String),
+    Load(String),
+    Check(String),
+    Incomplete(String),
+    /// A net could not be reduced, for example because of an arity mismatch.
+    Reduction(String),
+    /// Reduction hit the step, size or variable limit before it could decide.
+    Inconclusive(String),
+    Panic(String),
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypeError::Io(e) => write!(f, "I/O error: {}", e),
+            TypeError::Parse(e) => write!(f, "Parse error: {}", e),
+            TypeError::Load(e) => write!(f, "{}", e),
+            TypeError::Check(e) => write!(f, "{}", e),
+            TypeError::Incomplete(e) => write!(f, "Incomplete interaction system: {}", e),
+            TypeError::Reduction(e) => write!(f, "Reduction error: {}", e),
+            TypeError::Inconclusive(e) => write!(f, "{}", e),
+            TypeError::Panic(e) => write!(f, "Panicked: {}", e),
         }
     }
 }
 
+pub const DEFAULT_STEP_LIMIT: usize = 1_000_000;
+
+/// Source of the agents loaded by [`ProgramBuilder::load_prelude`].
+const PRELUDE: &str = include_str!("prelude.itt");
+
+/// A loaded program. Its `AgentId`s are only meaningful within this program;
+/// see [`ProgramBuilder`].
 pub struct Program {
     pub system: Rc<InteractionSystem>,
     pub agent_scope: BTreeMap<String, AgentId>,
-    pub agents: SlotMap<DefaultKey, ()>,
+    pub agents: SlotMap<DefaultKey, AgentMeta>,
     pub declarations: Vec<Declaration>,
     pub definitions: Vec<Definition>,
     pub checks: Vec<(bool, Net)>,
+    /// Id of the builder statement each check came from.
+    pub check_statements: Vec<usize>,
+    /// Name each check was given, if any.
+    pub check_names: Vec<Option<String>>,
+    pub warnings: Vec<String>,
+    /// Where each agent occurs in the source the program was built from.
+    /// Occurrences in the prelude or in rules made by the typechecker aren't included.
+    pub agent_spans: BTreeMap<AgentId, Vec<Span>>,
+    /// Makes [`Program::run_net`] fail on pairs without a rule, for programs
+    /// that are evaluated rather than typechecked.
+    pub strict: bool,
+    /// Makes [`Program::run_net`] reduce with [`Net::normal_interned`].
+    pub interned: bool,
+    /// Maximum number of reduction steps a check may take.
+    pub step_limit: usize,
+    /// Maximum [`Net::size`] a check may reach, if any.
+    pub size_limit: Option<usize>,
+    /// Maximum number of variable slots a check may have allocated at once, if any.
+    pub var_limit: Option<usize>,
     pub annotator_id: DefaultKey,
     pub ann_id: DefaultKey,
+    /// Internal agents that copy and negate type parameters, if any
+    /// declaration has them.
+    pub dup_id: Option<DefaultKey>,
+    pub neg_id: Option<DefaultKey>,
 }
 
 impl Program {
-    fn typecheck_net(&self, mut net: Net) -> Result<(), String> {
+    /// Whether `id` is one of the agents the typechecker introduces itself.
+    fn is_internal(&self, id: AgentId) -> bool {
+        id == self.ann_id
+            || id == self.annotator_id
+            || Some(id) == self.dup_id
+            || Some(id) == self.neg_id
+    }
+    /// Shows `tree` with the variables bound in `net` substituted in.
+    fn show_resolved(
+        &self,
+        net: &Net,
+        show_var: &mut dyn FnMut(VarId) -> String,
+        tree: &Tree,
+    ) -> String {
+        net.show_tree(
+            &|key| self.lookup_agent(&key).unwrap_or("?".to_string()),
+            show_var,
+            true,
+            &net.substitute_ref(tree),
+        )
+    }
+    fn typecheck_net(&self, mut net: Net) -> Result<(), TypeError> {
+        self.annotate_pairs(&mut net);
+        self.reduce_annotated(&mut net)
+    }
+    /// Typechecks `net` without stopping at the first undefined interaction:
+    /// each one is recorded and its pair set aside, and reduction goes on
+    /// with the rest of the net. If there were several, a last error counts
+    /// them by pair of agents. Errors that leave the net unusable, such as
+    /// running out of steps, are still returned on their own.
+    pub fn typecheck_net_all(&self, mut net: Net) -> Result<Vec<TypeError>, TypeError> {
+        let mut errors = vec![];
+        self.annotate_pairs(&mut net);
+        self.reduce_annotated_with(&mut net, &mut |e| {
+            errors.push(e);
+            Ok(())
+        })?;
+        if net.stuck.len() > 1 {
+            let mut e = format!("Had {} undefined interactions:", net.stuck.len());
+            for ((a, b), n) in net.count_stuck_by_pair() {
+                e.push_str(&format!(
+                    "\n\t{} ~ {}: {}",
+                    self.lookup_agent(&a).unwrap_or("?".to_string()),
+                    self.lookup_agent(&b).unwrap_or("?".to_string()),
+                    n
+                ));
+            }
+            errors.push(TypeError::Check(e));
+        }
+        Ok(errors)
+    }
+    /// Connects both sides of every pending pair to annotators sharing a variable.
+    fn annotate_pairs(&self, net: &mut Net) {
         for (a, b) in core::mem::take(&mut net.interactions).into_iter() {
             let v = net.new_var();
             net.interactions.push((
@@ -278,55 +1126,295 @@ impl Program {
                 },
             ));
         }
+    }
+    /// Reduces a net whose trees are connected to annotators, resolving
+    /// annotations as they get stuck against the agents they annotate.
+    fn reduce_annotated(&self, net: &mut Net) -> Result<(), TypeError> {
+        self.reduce_annotated_with(net, &mut Err)
+    }
+    /// Like [`Program::reduce_annotated`], but each undefined interaction is
+    /// passed to `on_error`, which decides whether to stop. If it doesn't,
+    /// reduction continues without the pair, which is left in `net.stuck`.
+    fn reduce_annotated_with(
+        &self,
+        net: &mut Net,
+        on_error: &mut dyn FnMut(TypeError) -> Result<(), TypeError>,
+    ) -> Result<(), TypeError> {
         net.system = self.system.clone();
-        let mut gc = vec![];
+        let mut steps = 0;
+        let mut undefined = vec![];
+        let start = net.size() as isize - net.growth;
 
-        //print!("------------------------\n{}", net.show_net(&|key| self.lookup_agent(&key).unwrap_or("?".to_string()), &mut BTreeMap::new()));
+        //print!("------------------------\n{}", net.show_net(&|key| self.lookup_agent(&key).unwrap_or("?".to_string()), &mut run::var_namer(), run::ShowMode::Resolved));
         while let Some((is_stuck, (a, b))) = net
             .interactions
             .pop()
             .map(|x| (false, x))
             .or_else(|| net.stuck.pop().map(|x| (true, x)))
         {
+            if steps == self.step_limit {
+                net.interactions.push((a, b));
+                return Err(TypeError::Inconclusive(format!(
+                    "inconclusive: exceeded {} steps, {} interactions remaining",
+                    self.step_limit,
+                    net.interactions.len() + net.stuck.len()
+                )));
+            }
+            steps += 1;
+            if let Some(limit) = self.size_limit
+                && start + net.growth > limit as isize
+            {
+                return Err(TypeError::Inconclusive(format!(
+                    "inconclusive: net size exceeded {}",
+                    limit
+                )));
+            }
+            if let Some(limit) = self.var_limit
+                && net.vars.len() > limit
+            {
+                return Err(TypeError::Inconclusive(format!(
+                    "inconclusive: variable budget exceeded {}",
+                    limit
+                )));
+            }
             if is_stuck {
-                let (a, b) = if b.agent_id().unwrap() == self.ann_id {
-                    (b, a)
-                } else {
-                    (a, b)
-                };
-                if a.agent_id().unwrap() == self.ann_id {
-                    let Tree::Agent { mut aux, .. } = a else {
+                // Only a well-formed annotation, with its term and type ports,
+                // can be resolved. Anything else stuck is a missing rule.
+                let is_ann = |x: &Tree| matches!(x, Tree::Agent { id, aux } if *id == self.ann_id && aux.len() == 2);
+                let (a, b) = if is_ann(&b) { (b, a) } else { (a, b) };
+                if is_ann(&a)
+                    && let Tree::Agent { aux, .. } = a
+                {
+                    let Ok([term, r#type]) = <[Tree; 2]>::try_from(aux) else {
                         unreachable!()
                     };
-                    gc.push(aux.pop());
-                    net.interact(aux.pop().unwrap(), b);
+                    // The annotation's type isn't needed past this point.
+                    net.growth -= 1 + r#type.size() as isize;
+                    net.erase(r#type);
+                    net.interact(term, b).map_err(|e| self.net_error(e))?;
                 } else {
-                    return Err(format!(
+                    let mut show_var = run::var_namer();
+                    on_error(TypeError::Check(format!(
                         "When typechecking net\n:\tUndefined Interaction:\n\t\t{ea} ~ {eb}",
-                        ea = self.lookup_agent(&a.agent_id().unwrap()).unwrap(),
-                        eb = self.lookup_agent(&b.agent_id().unwrap()).unwrap()
-                    ));
+                        ea = self.show_resolved(net, &mut show_var, &a),
+                        eb = self.show_resolved(net, &mut show_var, &b)
+                    )))?;
+                    undefined.push((a, b));
                 }
             } else {
-                net.interact(a, b)
+                net.interact(a, b).map_err(|e| self.net_error(e))?;
             }
-            //print!("{}", net.show_net(&|key| self.lookup_agent(&key).unwrap_or("?".to_string()), &mut BTreeMap::new()));
+            //print!("{}", net.show_net(&|key| self.lookup_agent(&key).unwrap_or("?".to_string()), &mut run::var_namer(), run::ShowMode::Resolved));
         }
-        if !net.stuck.is_empty() {
-            Err("Had stuck interactions".to_string())
+        net.stuck = undefined;
+        Ok(())
+    }
+    /// Lowers a standalone net against this program's agents, returning it
+    /// together with the variables bound to each name.
+    fn load_net(&self, net: syntax::Net) -> Result<(Net, BTreeMap<String, VarId>), TypeError> {
+        Net::from_syntax(net, &|name| self.agent_scope.get(name).copied()).map_err(TypeError::Load)
+    }
+    /// Reduces `net` to normal form under this program's rules. In `strict`
+    /// mode, the first pair without a rule is an error.
+    pub fn run_net(&self, mut net: Net) -> Result<Net, TypeError> {
+        net.system = self.system.clone();
+        net.strict = self.strict;
+        net.size_limit = self.size_limit;
+        let res = if self.interned {
+            net.normal_interned()
         } else {
-            Ok(())
+            net.normal()
+        };
+        res.map_err(|e| self.net_error(e))?;
+        Ok(net)
+    }
+    fn net_error(&self, e: NetError) -> TypeError {
+        match e {
+            NetError::ArityMismatch {
+                agent,
+                other,
+                expected,
+                found,
+            } => TypeError::Reduction(format!(
+                "Agent {} has {} ports when interacting with {}, but the rule expects {}",
+                self.lookup_agent(&agent).unwrap_or("?".to_string()),
+                found,
+                self.lookup_agent(&other).unwrap_or("?".to_string()),
+                expected
+            )),
+            NetError::NotPending { .. } => TypeError::Reduction(e.to_string()),
+            NetError::Stuck(a, b) => {
+                let show_agent = |key| self.lookup_agent(&key).unwrap_or("?".to_string());
+                let mut show_var = run::var_namer();
+                let net = Net::default();
+                TypeError::Reduction(format!(
+                    "No rule for the pair {} ~ {}",
+                    net.show_tree(&show_agent, &mut show_var, false, &a),
+                    net.show_tree(&show_agent, &mut show_var, false, &b)
+                ))
+            }
+            NetError::SizeLimit(limit) => {
+                TypeError::Inconclusive(format!("inconclusive: net size exceeded {}", limit))
+            }
         }
     }
-    fn check_well_typedness(&mut self) {
-        for (should_check, net) in core::mem::take(&mut self.checks) {
-            let res = self.typecheck_net(net);
-            if !should_check {
-                res.unwrap_err();
-            } else {
-                res.unwrap();
+    /// Reduces `net` and reads back the trees connected to each of the
+    /// variables named in `outputs`. Each output has to be a free port of
+    /// the net, that is, a variable that occurs exactly once.
+    pub fn eval(
+        &self,
+        net: syntax::Net,
+        outputs: &[&str],
+    ) -> Result<BTreeMap<String, Tree>, TypeError> {
+        let (net, vars) = self.load_net(net)?;
+        let mut net = self.run_net(net)?;
+        let mut res = BTreeMap::new();
+        for name in outputs {
+            let id = match vars.get(*name) {
+                Some(id) if net.vars.contains_key(*id) => *id,
+                Some(_) => {
+                    return Err(TypeError::Load(format!(
+                        "Output {} is connected inside the net, not a free port",
+                        name
+                    )))
+                }
+                None => return Err(TypeError::Load(format!("Unknown output port {}", name))),
+            };
+            res.insert(name.to_string(), net.substitute(Tree::Var { id }));
+        }
+        Ok(res)
+    }
+    /// Infers the type of a single tree. Variables in `tree` are taken to be
+    /// free ports and don't need to belong to any net.
+    pub fn typecheck_tree(&self, tree: Tree) -> Result<Tree, TypeError> {
+        let mut net = Net::default();
+        let mut vars = BTreeSet::new();
+        tree.collect_vars(&mut vars);
+        let map = vars.into_iter().map(|x| (x, net.new_var())).collect();
+        let tree = tree.rename_vars(&map);
+        let v = net.new_var();
+        net.interactions.push((
+            tree,
+            Tree::Agent {
+                id: self.annotator_id,
+                aux: vec![Tree::Var { id: v }],
+            },
+        ));
+        self.reduce_annotated(&mut net)?;
+        match net.substitute(Tree::Var { id: v }) {
+            Tree::Agent { id, mut aux } if id == self.ann_id && aux.len() == 2 => {
+                Ok(aux.pop().unwrap())
+            }
+            _ => Err(TypeError::Check(
+                "Tree was not annotated with a type".to_string(),
+            )),
+        }
+    }
+    /// Runs every check. On success, returns the error each negative check
+    /// failed with, so it can be confirmed it failed for the intended reason.
+    fn check_well_typedness(&mut self) -> Result<Vec<(usize, TypeError)>, TypeError> {
+        let mut negatives = vec![];
+        for index in 0..self.checks.len() {
+            if let Some(e) = self.run_check(index)? {
+                negatives.push((index, e));
             }
         }
+        self.checks.clear();
+        self.check_statements.clear();
+        self.check_names.clear();
+        Ok(negatives)
+    }
+    /// Groups the negative checks returned by `check_well_typedness` by the
+    /// exact message they failed with. Only messages shared by more than one
+    /// check are returned, since those checks are likely not testing what
+    /// they were meant to.
+    pub fn group_negative_errors(negatives: &[(usize, TypeError)]) -> BTreeMap<String, Vec<usize>> {
+        let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (index, e) in negatives {
+            groups.entry(e.to_string()).or_default().push(*index);
+        }
+        groups.retain(|_, x| x.len() > 1);
+        groups
+    }
+    /// Runs a check and, if it passes, infers the type of the left side of
+    /// its pair on its own, by annotating it and reading back the type its
+    /// annotation ends up with. A negative check returns the error it failed with.
+    pub fn type_of_check(&self, index: usize) -> Result<Tree, TypeError> {
+        if let Some(e) = self.run_check(index)? {
+            return Err(e);
+        }
+        let (_, mut net) = self.get_check(index)?.clone();
+        let (a, _) = net.interactions.remove(0);
+        // What is left are the `with` redexes, which get checked as usual.
+        self.annotate_pairs(&mut net);
+        let v = net.new_var();
+        net.interactions.push((
+            a,
+            Tree::Agent {
+                id: self.annotator_id,
+                aux: vec![Tree::Var { id: v }],
+            },
+        ));
+        self.reduce_annotated(&mut net)?;
+        match net.substitute(Tree::Var { id: v }) {
+            Tree::Agent { id, mut aux } if id == self.ann_id && aux.len() == 2 => {
+                Ok(aux.pop().unwrap())
+            }
+            _ => Err(TypeError::Check(format!(
+                "The left side of check {} has no type",
+                index
+            ))),
+        }
+    }
+    /// Every undefined interaction a positive check runs into, found in a
+    /// single pass with [`Program::typecheck_net_all`]. Negative checks are
+    /// expected to fail, so they are left to [`Program::run_check`].
+    pub fn check_errors(&self, index: usize) -> Result<Vec<TypeError>, TypeError> {
+        let (should_check, net) = self.get_check(index)?.clone();
+        if !should_check {
+            return Ok(vec![]);
+        }
+        match self.typecheck_net_all(net) {
+            Err(TypeError::Inconclusive(e)) => {
+                Err(TypeError::Inconclusive(format!("Check {} is {}", index, e)))
+            }
+            Err(e) => Err(TypeError::Check(format!("Check {} failed:\n{}", index, e))),
+            Ok(errors) => Ok(errors
+                .into_iter()
+                .map(|e| TypeError::Check(format!("Check {} failed:\n{}", index, e)))
+                .collect()),
+        }
+    }
+    fn get_check(&self, index: usize) -> Result<&(bool, Net), TypeError> {
+        self.checks
+            .get(index)
+            .ok_or_else(|| TypeError::Check(format!("There is no check {}", index)))
+    }
+    /// Runs the check with the given name, like [`Program::run_check`].
+    pub fn run_check_named(&self, name: &str) -> Result<Option<TypeError>, TypeError> {
+        let index = self
+            .check_names
+            .iter()
+            .position(|x| x.as_deref() == Some(name))
+            .ok_or_else(|| TypeError::Check(format!("There is no check named {:?}", name)))?;
+        self.run_check(index)
+    }
+    /// Runs a single check. A negative check that fails as expected returns
+    /// the error it failed with.
+    pub fn run_check(&self, index: usize) -> Result<Option<TypeError>, TypeError> {
+        let (should_check, net) = self.get_check(index)?.clone();
+        match (should_check, self.typecheck_net(net)) {
+            (_, Err(TypeError::Inconclusive(e))) => {
+                Err(TypeError::Inconclusive(format!("Check {} is {}", index, e)))
+            }
+            (true, Err(e)) => Err(TypeError::Check(format!("Check {} failed:\n{}", index, e))),
+            (false, Ok(())) => Err(TypeError::Check(format!(
+                "Check {} was expected to fail, but passed",
+                index
+            ))),
+            (false, Err(e)) => Ok(Some(e)),
+            (true, Ok(())) => Ok(None),
+        }
     }
     fn get_nth_instances(&self, t: AgentId, d: usize) -> impl Iterator<Item = AgentId> + Clone {
         let mut v = vec![];
@@ -343,17 +1431,135 @@ impl Program {
         v.into_iter()
     }
     fn lookup_agent(&self, id: &AgentId) -> Option<String> {
+        self.agents.get(*id).map(|x| x.name.clone())
+    }
+    /// Declared agents that never appear in a user-written rule, and so can never reduce.
+    pub fn agents_without_rules(&self) -> Vec<AgentId> {
+        let with_rules: BTreeSet<AgentId> = self
+            .definitions
+            .iter()
+            .filter(|x| !self.is_internal(x.left.id))
+            .flat_map(|x| [x.left.id, x.right.id])
+            .collect();
+        let declared: BTreeSet<AgentId> = self.declarations.iter().map(|x| x.agent.id).collect();
+        declared.difference(&with_rules).copied().collect()
+    }
+    /// Name and arity of every agent, leaving out internal ones. The arity is
+    /// the declared one, or else the one it's used with in declarations and rules.
+    pub fn signature(&self) -> BTreeMap<String, Option<usize>> {
+        fn visit(tree: &Tree, out: &mut BTreeMap<AgentId, usize>) {
+            if let Tree::Agent { id, aux } = tree {
+                out.entry(*id).or_insert(aux.len());
+                aux.iter().for_each(|x| visit(x, out));
+            }
+        }
+        let mut used = BTreeMap::new();
+        for decl in &self.declarations {
+            used.entry(decl.agent.id).or_insert(decl.agent.aux.len());
+            for (from, to, r#type) in &decl.agent.aux {
+                [from, to, r#type]
+                    .into_iter()
+                    .for_each(|x| visit(x, &mut used));
+            }
+            decl.intermediate.iter().for_each(|x| visit(x, &mut used));
+            visit(&decl.r#type.clone().into(), &mut used);
+        }
+        for def in &self.definitions {
+            visit(&def.left.clone().into(), &mut used);
+            visit(&def.right.clone().into(), &mut used);
+        }
         self.agent_scope
             .iter()
-            .find(|(_, v)| *v == id)
-            .map(|x| x.0.to_string())
+            .filter(|(_, id)| !self.is_internal(**id))
+            .map(|(name, id)| {
+                (
+                    name.clone(),
+                    self.agents[*id].arity.or(used.get(id).copied()),
+                )
+            })
+            .collect()
     }
-    fn require_defined(&self, a: AgentId, b: AgentId) -> Result<(), String> {
-        let defined = self
-            .definitions
+    /// Compares the signatures of two programs, reporting every agent that is
+    /// missing from either of them or that has different arities.
+    pub fn signature_compatible_with(&self, other: &Program) -> Result<(), Vec<String>> {
+        let show = |x: Option<usize>| x.map_or("unknown".to_string(), |x| x.to_string());
+        let (ours, theirs) = (self.signature(), other.signature());
+        let mut errors = vec![];
+        for (name, arity) in &ours {
+            match theirs.get(name) {
+                None => errors.push(format!("Agent {} is missing from the other program", name)),
+                Some(other) if other != arity => errors.push(format!(
+                    "Agent {} has arity {} here, but {} in the other program",
+                    name,
+                    show(*arity),
+                    show(*other)
+                )),
+                Some(_) => (),
+            }
+        }
+        for name in theirs.keys().filter(|x| !ours.contains_key(*x)) {
+            errors.push(format!("Agent {} is only in the other program", name));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// Declared type of each auxiliary port of `agent`. Variables in the
+    /// returned trees refer to the declaration's own net.
+    pub fn port_types(&self, agent: AgentId) -> Option<Vec<Tree>> {
+        self.declarations
+            .iter()
+            .find(|x| x.agent.id == agent)
+            .map(|x| x.agent.aux.iter().map(|x| x.2.clone()).collect())
+    }
+    /// Position of the agent in name order. Unlike the `AgentId` itself,
+    /// this doesn't depend on the order agents were first seen in.
+    pub fn agent_index(&self, id: &AgentId) -> Option<usize> {
+        self.agent_scope.values().position(|x| x == id)
+    }
+    /// Sorts rules by the names of their left and right agents, and
+    /// declarations by the name of their agent, so that the order they are
+    /// shown in doesn't depend on the order they were loaded in.
+    pub fn sort_definitions(&mut self) {
+        let agents = &self.agents;
+        let name = |id: AgentId| agents.get(id).map(|x| x.name.clone()).unwrap_or_default();
+        self.definitions
+            .sort_by_cached_key(|x| (name(x.left.id), name(x.right.id)));
+        self.declarations.sort_by_cached_key(|x| name(x.agent.id));
+    }
+    /// Shows the rules synthesized from declarations, one per line, with the
+    /// variables of each rule numbered separately. Active pairs in a rule's
+    /// body are listed after `with`.
+    pub fn dump_annotator_rules(&self) -> String {
+        let show_agent = |key| self.lookup_agent(&key).unwrap_or("?".to_string());
+        let mut s = String::new();
+        for def in &self.definitions {
+            if def.left.id != self.annotator_id || def.right.id == self.annotator_id {
+                continue;
+            }
+            s.push_str(&def.show(&show_agent));
+            s.push('\n');
+        }
+        s
+    }
+    /// The rule between the agents named `a` and `b`, in whichever orientation
+    /// it was defined. `None` if either name is unknown or there is no rule.
+    pub fn interaction_between(&self, a: &str, b: &str) -> Option<&InteractionRule> {
+        let a = *self.agent_scope.get(a)?;
+        let b = *self.agent_scope.get(b)?;
+        self.system
+            .get_rule(a, b)
+            .or_else(|| self.system.get_rule(b, a))
+    }
+    fn is_defined(&self, a: AgentId, b: AgentId) -> bool {
+        self.definitions
             .iter()
-            .any(|x| x.left.id == a && x.right.id == b || x.left.id == b && x.right.id == a);
-        if !defined {
+            .any(|x| x.left.id == a && x.right.id == b || x.left.id == b && x.right.id == a)
+    }
+    fn require_defined(&self, a: AgentId, b: AgentId) -> Result<(), String> {
+        if !self.is_defined(a, b) {
             Err(format!(
                 "Undefined interaction between {} and {}",
                 self.lookup_agent(&a).unwrap(),
@@ -363,6 +1569,193 @@ impl Program {
             Ok(())
         }
     }
+    /// Agents that can show up while running the checks: everything mentioned by
+    /// checks and declarations, plus whatever reachable rules introduce in
+    /// their ports and bodies.
+    fn occurring_agents(&self) -> BTreeSet<AgentId> {
+        let mut occurring = BTreeSet::from([self.annotator_id, self.ann_id]);
+        occurring.extend(self.dup_id);
+        occurring.extend(self.neg_id);
+        for (_, net) in &self.checks {
+            for (a, b) in &net.interactions {
+                a.collect_agents(&mut occurring);
+                b.collect_agents(&mut occurring);
+            }
+        }
+        for decl in &self.declarations {
+            occurring.insert(decl.agent.id);
+            occurring.insert(decl.r#type.id);
+            decl.intermediate
+                .iter()
+                .chain(decl.r#type.aux.iter())
+                .for_each(|x| x.collect_agents(&mut occurring));
+        }
+        loop {
+            let before = occurring.len();
+            for def in &self.definitions {
+                if occurring.contains(&def.left.id) && occurring.contains(&def.right.id) {
+                    def.left
+                        .aux
+                        .iter()
+                        .chain(def.right.aux.iter())
+                        .chain(def.net.interactions.iter().flat_map(|(a, b)| [a, b]))
+                        .for_each(|x| x.collect_agents(&mut occurring));
+                }
+            }
+            if occurring.len() == before {
+                return occurring;
+            }
+        }
+    }
+    /// Whether `a` and `b` may ever form an active pair, either while
+    /// reducing the checks or in a net that connects declared agents whose
+    /// types have a rule between them. This is conservative: `false` means
+    /// they never meet, `true` only that nothing rules it out. Variables are
+    /// assumed to connect whatever they may hold to anything else.
+    pub fn can_interact(&self, a: AgentId, b: AgentId) -> bool {
+        self.possible_pairs().contains(&(a.min(b), a.max(b)))
+    }
+    /// Every pair [`Program::can_interact`] allows, with the smaller id first.
+    fn possible_pairs(&self) -> BTreeSet<(AgentId, AgentId)> {
+        type Held = BTreeMap<(AgentId, usize), BTreeSet<AgentId>>;
+        // Agents that may sit at each aux port, and ports that may hold a variable.
+        fn collect(tree: &Tree, held: &mut Held, wired: &mut BTreeSet<(AgentId, usize)>) {
+            if let Tree::Agent { id, aux } = tree {
+                for (i, x) in aux.iter().enumerate() {
+                    match x.agent_id() {
+                        Some(child) => held.entry((*id, i)).or_default().insert(child),
+                        None => wired.insert((*id, i)),
+                    };
+                    collect(x, held, wired);
+                }
+            }
+        }
+        let mut held = Held::new();
+        let mut wired = BTreeSet::new();
+        for (_, net) in &self.checks {
+            for (x, y) in &net.interactions {
+                collect(x, &mut held, &mut wired);
+                collect(y, &mut held, &mut wired);
+            }
+        }
+        for def in &self.definitions {
+            for x in def.left.aux.iter().chain(def.right.aux.iter()) {
+                collect(x, &mut held, &mut wired);
+            }
+            for (x, y) in &def.net.interactions {
+                collect(x, &mut held, &mut wired);
+                collect(y, &mut held, &mut wired);
+            }
+        }
+        // The result type of each declared agent, along with its intermediate types.
+        let types: BTreeMap<AgentId, BTreeSet<AgentId>> = self
+            .declarations
+            .iter()
+            .map(|x| {
+                let mut types = BTreeSet::from([x.r#type.id]);
+                types.extend(x.intermediate.iter().filter_map(Tree::agent_id));
+                (x.agent.id, types)
+            })
+            .collect();
+        let meet = |x: &BTreeSet<AgentId>, y: &BTreeSet<AgentId>| {
+            iproduct!(x, y).any(|(x, y)| self.is_defined(*x, *y))
+        };
+        let mut pairs = BTreeSet::new();
+        // Agents that may end up at one end of a variable.
+        let mut loose = BTreeSet::new();
+        let seed = |x: &Tree, y: &Tree, pairs: &mut BTreeSet<_>, loose: &mut BTreeSet<_>| match (
+            x.agent_id(),
+            y.agent_id(),
+        ) {
+            (Some(x), Some(y)) => {
+                pairs.insert((x.min(y), x.max(y)));
+            }
+            (Some(x), None) | (None, Some(x)) => {
+                loose.insert(x);
+            }
+            (None, None) => (),
+        };
+        for (_, net) in &self.checks {
+            for (x, y) in &net.interactions {
+                seed(x, y, &mut pairs, &mut loose);
+            }
+        }
+        for decl in &self.declarations {
+            let id = decl.agent.id;
+            for (i, (_, _, port_type)) in decl.agent.aux.iter().enumerate() {
+                wired.insert((id, i));
+                if let Some(port_type) = port_type.agent_id() {
+                    let port_type = BTreeSet::from([port_type]);
+                    let fits = types.iter().filter(|x| meet(&port_type, x.1));
+                    held.entry((id, i)).or_default().extend(fits.map(|x| *x.0));
+                }
+            }
+            for (other, other_types) in &types {
+                if meet(&types[&id], other_types) {
+                    pairs.insert((id.min(*other), id.max(*other)));
+                }
+            }
+        }
+        loop {
+            let before = (pairs.len(), loose.len());
+            for def in &self.definitions {
+                let (l, r) = (def.left.id, def.right.id);
+                if !pairs.contains(&(l.min(r), l.max(r))) {
+                    continue;
+                }
+                for (x, y) in &def.net.interactions {
+                    seed(x, y, &mut pairs, &mut loose);
+                }
+                // Each port of the rule gets linked to what was at the
+                // matching aux port of the agent it replaces.
+                for (id, ports) in [(l, &def.left.aux), (r, &def.right.aux)] {
+                    for (i, port) in ports.iter().enumerate() {
+                        let outside = held.get(&(id, i)).cloned().unwrap_or_default();
+                        match port.agent_id() {
+                            Some(root) => {
+                                for x in outside {
+                                    pairs.insert((root.min(x), root.max(x)));
+                                }
+                                if wired.contains(&(id, i)) {
+                                    loose.insert(root);
+                                }
+                            }
+                            None => loose.extend(outside),
+                        }
+                    }
+                }
+            }
+            for (x, y) in iproduct!(&loose, &loose) {
+                pairs.insert((*x.min(y), *x.max(y)));
+            }
+            if (pairs.len(), loose.len()) == before {
+                return pairs;
+            }
+        }
+    }
+    /// Rules whose active pair can never be formed from the checks and declarations.
+    pub fn unreachable_rules(&self) -> Vec<(AgentId, AgentId)> {
+        let occurring = self.occurring_agents();
+        self.definitions
+            .iter()
+            .filter(|def| !occurring.contains(&def.left.id) || !occurring.contains(&def.right.id))
+            .map(|def| (def.left.id, def.right.id))
+            .collect()
+    }
+    /// Every pair of agents `check_completeness` requires a rule for,
+    /// along with whether that rule exists.
+    pub fn completeness_obligations(&self) -> Vec<(AgentId, AgentId, bool)> {
+        let mut obligations = vec![];
+        for def in &self.definitions {
+            for (i, j) in iproduct!(
+                self.get_nth_instances(def.left.id, 0),
+                self.get_nth_instances(def.right.id, 0)
+            ) {
+                obligations.push((i, j, self.is_defined(i, j)));
+            }
+        }
+        obligations
+    }
     pub fn check_completeness(&self) -> Result<(), String> {
         for def in &self.definitions {
             // Look for "child" interactions
@@ -380,36 +1773,458 @@ impl Program {
 impl std::fmt::Display for Program {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("Rules:\n")?;
-        for (a, m) in &self.system.rules {
-            for (b, _) in m {
-                f.write_fmt(format_args!(
-                    "\t{} ~ {}\n",
-                    self.lookup_agent(a).unwrap(),
-                    self.lookup_agent(b).unwrap()
-                ))?
-            }
+        for def in &self.definitions {
+            f.write_fmt(format_args!(
+                "\t{} ~ {}\n",
+                self.lookup_agent(&def.left.id).unwrap(),
+                self.lookup_agent(&def.right.id).unwrap()
+            ))?
         }
         f.write_str("Scope:\n")?;
         for (n, id) in &self.agent_scope {
-            write!(f, "\t{:?} {:?}\n", n, id)?;
+            write!(f, "\t{:?} #{}\n", n, self.agent_index(id).unwrap())?;
         }
         // todo print more things..
         Ok(())
     }
 }
 
-fn main() {
-    let code = std::fs::read_to_string(std::env::args().skip(1).next().unwrap()).unwrap();
-    let mut parser = CodeParser::new(&code);
-    let ast = parser.parse_book();
-    let Ok(ast) = ast else {
-        eprintln!("{}", ast.unwrap_err());
-        return;
+/// Parses and builds a program without running its checks.
+pub fn validate_syntax_and_build(code: &str) -> Result<Program, TypeError> {
+    build_with(code, ProgramBuilder::default())
+}
+
+/// Like [`validate_syntax_and_build`], but loads the program into `builder`.
+pub fn build_with(code: &str, mut builder: ProgramBuilder) -> Result<Program, TypeError> {
+    let ast = CodeParser::new(code)
+        .parse_book()
+        .map_err(TypeError::Parse)?;
+    builder.load_book(ast).map_err(TypeError::Load)?;
+    builder.finish()
+}
+
+/// Parses, builds and checks a whole program.
+pub fn check_source(code: &str) -> Result<(), TypeError> {
+    let mut program = validate_syntax_and_build(code)?;
+    program.check_well_typedness()?;
+    program.check_completeness().map_err(TypeError::Incomplete)
+}
+
+/// Checks every `.tas` or `.itt` file in `path` independently, in file name order.
+/// A panic while checking one file is reported as that file's error.
+pub fn check_directory(path: impl AsRef<Path>) -> Vec<(PathBuf, Result<(), TypeError>)> {
+    let path = path.as_ref();
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => return vec![(path.to_path_buf(), Err(TypeError::Io(e.to_string())))],
     };
-    let mut program = ProgramBuilder::default();
-    program.load_book(ast);
-    let mut program = program.finish();
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .filter(|x| x.is_file() && x.extension().is_some_and(|e| e == "tas" || e == "itt"))
+        .collect();
+    files.sort();
+    files
+        .into_iter()
+        .map(|file| {
+            let res = match std::fs::read_to_string(&file) {
+                Ok(code) => std::panic::catch_unwind(|| check_source(&code)).unwrap_or_else(|e| {
+                    let msg = e
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| e.downcast_ref::<&str>().map(|x| x.to_string()))
+                        .unwrap_or_default();
+                    Err(TypeError::Panic(msg))
+                }),
+                Err(e) => Err(TypeError::Io(e.to_string())),
+            };
+            (file, res)
+        })
+        .collect()
+}
+
+const USAGE: &str = "\
+usage: typed-agents [COMMAND] FILE [OPTIONS]
+       typed-agents check FILE... [OPTIONS]
+       typed-agents check DIR
+
+commands:
+    check   typecheck the program and run its checks (the default); with a
+            directory, check every .tas and .itt file in it and summarize the results
+            --step-limit N, --size-limit N, --var-limit N
+            --only NAME: only run the check with that name
+            --verbose annotators: print the annotator rule of each declaration
+            --errors all: report every undefined interaction of a check, not just the first
+    run     reduce the net of every check and print the result
+            --emit-dot DIR: also write each result to DIR/check_N.dot
+            --trace diff: print each reduced pair and the pairs it produced
+            --reducer interned: reduce with hash-consed trees instead
+            --size-limit N: give up once the net has more than N agents
+    graph   print the net of every check in Graphviz format
+    fmt     rewrite the file in a canonical layout, keeping its comments
+            --output PATH: write the result to PATH instead, or print it if PATH is -
+
+options for every command:
+    --json  report parse errors as a JSON object with line, col and message";
+
+/// Reads `--name value` pairs, rejecting options not in `allowed`.
+fn parse_options(args: &[String], allowed: &[&str]) -> Result<BTreeMap<String, String>, String> {
+    let mut options = BTreeMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(name) = arg.strip_prefix("--").filter(|x| allowed.contains(x)) else {
+            return Err(format!("Unknown option {}", arg));
+        };
+        let Some(value) = args.next() else {
+            return Err(format!("Option {} needs a value", arg));
+        };
+        options.insert(name.to_string(), value.clone());
+    }
+    Ok(options)
+}
+
+fn parse_limit(options: &BTreeMap<String, String>, name: &str) -> Result<Option<usize>, String> {
+    options
+        .get(name)
+        .map(|x| {
+            x.parse()
+                .map_err(|_| format!("--{} expects a number, got {}", name, x))
+        })
+        .transpose()
+}
+
+fn cmd_check(code: &str, args: &[String]) -> Result<(), String> {
+    let options = parse_options(
+        args,
+        &[
+            "step-limit",
+            "size-limit",
+            "var-limit",
+            "only",
+            "verbose",
+            "errors",
+        ],
+    )?;
+    let all_errors = match options.get("errors").map(|x| x.as_str()) {
+        Some("all") => true,
+        Some("first") | None => false,
+        Some(what) => return Err(format!("Unknown errors mode {}", what)),
+    };
+    let mut builder = ProgramBuilder::default();
+    match options.get("verbose").map(|x| x.as_str()) {
+        Some("annotators") => builder.verbose = true,
+        Some(what) => return Err(format!("Unknown verbose mode {}", what)),
+        None => (),
+    }
+    let mut program = build_with(code, builder).map_err(|e| e.to_string())?;
+    if let Some(limit) = parse_limit(&options, "step-limit")? {
+        program.step_limit = limit;
+    }
+    program.size_limit = parse_limit(&options, "size-limit")?.or(program.size_limit);
+    program.var_limit = parse_limit(&options, "var-limit")?.or(program.var_limit);
+    program.sort_definitions();
     println!("{}", program);
-    program.check_well_typedness();
-    program.check_completeness().unwrap();
+    for warning in &program.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for agent in program.agents_without_rules() {
+        eprintln!(
+            "warning: {} is declared but has no interaction rules",
+            program.lookup_agent(&agent).unwrap()
+        );
+    }
+    let negatives = if all_errors {
+        let indices: Vec<usize> = match options.get("only") {
+            Some(name) => vec![program
+                .check_names
+                .iter()
+                .position(|x| x.as_deref() == Some(name))
+                .ok_or_else(|| format!("There is no check named {:?}", name))?],
+            None => (0..program.checks.len()).collect(),
+        };
+        let mut negatives = vec![];
+        let mut failed = 0;
+        for index in indices {
+            if !program.checks[index].0 {
+                if let Some(e) = program.run_check(index).map_err(|e| e.to_string())? {
+                    negatives.push((index, e));
+                }
+                continue;
+            }
+            let errors = program.check_errors(index).map_err(|e| e.to_string())?;
+            for e in &errors {
+                eprintln!("{}", e);
+            }
+            if !errors.is_empty() {
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(format!("{} checks failed", failed));
+        }
+        negatives
+    } else if let Some(name) = options.get("only") {
+        if let Some(e) = program.run_check_named(name).map_err(|e| e.to_string())? {
+            eprintln!("Check {:?} failed as expected:\n{}", name, e);
+        }
+        return Ok(());
+    } else {
+        program.check_well_typedness().map_err(|e| e.to_string())?
+    };
+    for (index, e) in &negatives {
+        eprintln!("Check {} failed as expected:\n{}", index, e);
+    }
+    for (_, indices) in Program::group_negative_errors(&negatives) {
+        eprintln!(
+            "warning: checks {} all fail with the same error",
+            indices
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if options.contains_key("only") {
+        return Ok(());
+    }
+    program
+        .check_completeness()
+        .map_err(|e| TypeError::Incomplete(e).to_string())
+}
+
+fn cmd_check_directory(path: &str, args: &[String]) -> Result<(), String> {
+    parse_options(args, &[])?;
+    let results = check_directory(path);
+    let mut failed = 0;
+    for (file, res) in &results {
+        match res {
+            Ok(()) => println!("{}: ok", file.display()),
+            Err(e) => {
+                println!("{}: failed", file.display());
+                eprintln!("{}", e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} files failed", failed, results.len()));
+    }
+    Ok(())
+}
+
+fn cmd_run(code: &str, args: &[String]) -> Result<(), String> {
+    let options = parse_options(args, &["emit-dot", "trace", "reducer", "size-limit"])?;
+    if let Some(mode) = options.get("trace")
+        && mode != "diff"
+    {
+        return Err(format!("Unknown trace mode {}", mode));
+    }
+    let mut program = validate_syntax_and_build(code).map_err(|e| e.to_string())?;
+    match options.get("reducer").map(|x| x.as_str()) {
+        Some("interned") => program.interned = true,
+        Some("tree") | None => (),
+        Some(what) => return Err(format!("Unknown reducer {}", what)),
+    }
+    program.size_limit = parse_limit(&options, "size-limit")?.or(program.size_limit);
+    let show_agent = |key| program.lookup_agent(&key).unwrap_or("?".to_string());
+    for (index, (_, net)) in program.checks.iter().enumerate() {
+        let mut net = net.clone();
+        if options.contains_key("trace") {
+            println!("Trace {}:", index);
+            net.system = program.system.clone();
+            let mut show_var = run::var_namer();
+            let mut show = |net: &Net, (a, b): &(Tree, Tree)| {
+                format!(
+                    "{} ~ {}",
+                    net.show_tree(&show_agent, &mut show_var, false, a),
+                    net.show_tree(&show_agent, &mut show_var, false, b)
+                )
+            };
+            while let Some(diff) = net
+                .step_diff()
+                .map_err(|e| program.net_error(e).to_string())?
+            {
+                println!("\t{}", show(&net, &diff.consumed));
+                for pair in &diff.added {
+                    println!("\t\t+ {}", show(&net, pair));
+                }
+            }
+        }
+        let net = program.run_net(net).map_err(|e| e.to_string())?;
+        print!(
+            "Check {}:\n{}",
+            index,
+            net.show_net(&show_agent, &mut run::var_namer(), run::ShowMode::Resolved)
+        );
+        if let Some(dir) = options.get("emit-dot") {
+            let path = Path::new(dir).join(format!("check_{}.dot", index));
+            std::fs::write(&path, net.to_dot(&show_agent))
+                .map_err(|e| TypeError::Io(format!("{}: {}", path.display(), e)).to_string())?;
+        }
+    }
+    Ok(())
+}
+
+fn cmd_fmt(path: &str, code: &str, args: &[String]) -> Result<(), String> {
+    let options = parse_options(args, &["output"])?;
+    let mut parser = CodeParser::new(code);
+    let book = parser
+        .parse_book()
+        .map_err(|e| TypeError::Parse(e).to_string())?;
+    let formatted = parser.format(&book)?;
+    match options.get("output").map(|x| x.as_str()) {
+        Some("-") => {
+            print!("{}", formatted);
+            Ok(())
+        }
+        output => {
+            let output = output.unwrap_or(path);
+            std::fs::write(output, formatted)
+                .map_err(|e| TypeError::Io(format!("{}: {}", output, e)).to_string())
+        }
+    }
+}
+
+fn cmd_graph(code: &str, args: &[String]) -> Result<(), String> {
+    parse_options(args, &[])?;
+    let program = validate_syntax_and_build(code).map_err(|e| e.to_string())?;
+    let show_agent = |key| program.lookup_agent(&key).unwrap_or("?".to_string());
+    for (index, (_, net)) in program.checks.iter().enumerate() {
+        print!("// Check {}\n{}", index, net.to_dot(&show_agent));
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.first().map(|x| x.as_str()) {
+        Some("check" | "run" | "graph" | "fmt") => args.remove(0),
+        Some(_) => "check".to_string(),
+        None => String::new(),
+    };
+    let json = match args.iter().position(|x| x == "--json") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let files = args
+        .iter()
+        .position(|x| x.starts_with("--"))
+        .unwrap_or(args.len());
+    let paths: Vec<String> = args.drain(..files).collect();
+    if paths.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    if paths.len() > 1 && command != "check" {
+        eprintln!("Only check takes several files");
+        std::process::exit(2);
+    }
+    // Each file is built into a program of its own, so nothing one of them
+    // defines is visible to the others.
+    let mut failed = false;
+    for path in &paths {
+        if paths.len() > 1 {
+            println!("{}:", path);
+        }
+        if let Err(e) = run_command(&command, path, json, &args) {
+            eprintln!("{}", e);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn run_command(command: &str, path: &str, json: bool, args: &[String]) -> Result<(), String> {
+    if command == "check" && Path::new(path).is_dir() {
+        return cmd_check_directory(path, args);
+    }
+    let code = std::fs::read_to_string(path)
+        .map_err(|e| TypeError::Io(format!("{}: {}", path, e)).to_string())?;
+    if json && let Err(e) = CodeParser::new(&code).parse_book_located() {
+        return Err(e.to_json());
+    }
+    match command {
+        "check" => cmd_check(&code, args),
+        "run" => cmd_run(&code, args),
+        "graph" => cmd_graph(&code, args),
+        _ => cmd_fmt(path, &code, args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn programs_sharing_a_scope_are_isolated() {
+        let scope = Rc::new(RefCell::new(AgentScope::default()));
+        let build = |code: &str, policy| {
+            let mut builder = ProgramBuilder::with_shared_scope(scope.clone());
+            builder.duplicate_agents = policy;
+            builder.load_prelude().unwrap();
+            build_with(code, builder)
+        };
+        let mut first = build(
+            "agent Foo/0
+            Foo: Bool
+            Foo ~ Era
+            Foo ~ Dup(Foo Foo)
+            Not(True) ~ Foo
+            check yes Foo ~ Not(x)",
+            DuplicateAgentPolicy::Error,
+        )
+        .unwrap();
+        // Same name, other arity.
+        let mut second = build(
+            "agent Foo/1
+            Foo(x -> x: Bool): !Bool
+            Foo(False) ~ True
+            check yes True ~ Foo(x)",
+            DuplicateAgentPolicy::Shadow,
+        )
+        .unwrap();
+        let foo = first.agent_scope["Foo"];
+        let other_foo = second.agent_scope["Foo"];
+        assert_ne!(foo, other_foo);
+        assert_eq!(first.agents[foo].arity, Some(0));
+        assert_eq!(second.agents[other_foo].arity, Some(1));
+        assert_eq!(second.lookup_agent(&other_foo).unwrap(), "Foo");
+        // The prelude is loaded by both, and shared.
+        assert_eq!(first.agent_scope["Bool"], second.agent_scope["Bool"]);
+        first.check_well_typedness().unwrap();
+        second.check_well_typedness().unwrap();
+        // Without asking to shadow it, the name can't be reused.
+        assert!(matches!(
+            build("Foo(x -> x: Bool): Bool", DuplicateAgentPolicy::default()),
+            Err(TypeError::Load(_))
+        ));
+    }
+
+    #[test]
+    fn rule_body_with_several_redexes() {
+        let program = validate_syntax_and_build(
+            "Add(y y) ~ Zero
+            Add(a Succ(b)) ~ Succ(Add(a b))
+            Add(y o with Add(y m) ~ Succ(Zero) with Add(m o) ~ Succ(Zero)) ~ Two
+            check yes Two ~ Add(Succ(Zero) x)",
+        )
+        .unwrap();
+        let net = program.checks[0].1.clone();
+        // `x`, the last port of `Add`.
+        let Some((_, Tree::Agent { aux, .. })) = net.interactions.first() else {
+            panic!("check net is not a single pair")
+        };
+        let x = aux[1].clone();
+        let net = program.run_net(net).unwrap();
+        let show_agent = |key| program.lookup_agent(&key).unwrap();
+        // Both redexes of the rule's body have to be reduced to get 3.
+        assert_eq!(
+            net.show_tree(&show_agent, &mut run::var_namer(), true, &x),
+            "Succ(Succ(Succ(Zero)))"
+        );
+    }
 }
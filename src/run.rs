@@ -1,19 +1,182 @@
-use slotmap::{DefaultKey, SlotMap};
-use std::{collections::BTreeMap, rc::Rc};
+use slotmap::{DefaultKey, Key, SlotMap};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+};
+
+use crate::syntax;
 
 pub type AgentId = DefaultKey;
 pub type VarId = DefaultKey;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Tree {
     Agent { id: AgentId, aux: Vec<Tree> },
     Var { id: VarId },
 }
 
+/// Prints agents as `@<id>` and variables as `$<id>` using their raw slotmap
+/// ids, without resolving anything. Use `Net::show_tree` for readable output.
+impl std::fmt::Display for Tree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tree::Agent { id, aux } => {
+                write!(f, "@{:?}", id.data())?;
+                if !aux.is_empty() {
+                    write!(f, "(")?;
+                    for (i, x) in aux.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, " ")?;
+                        }
+                        write!(f, "{}", x)?;
+                    }
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Tree::Var { id } => write!(f, "${:?}", id.data()),
+        }
+    }
+}
+
+/// Names variables `x0`, `x1`, ... in the order they are first shown.
+pub fn var_namer() -> impl FnMut(VarId) -> String {
+    let mut scope = BTreeMap::new();
+    move |id| {
+        let l = scope.len();
+        scope.entry(id).or_insert_with(|| format!("x{}", l)).clone()
+    }
+}
+
+impl Tree {
+    /// Number of agents in the tree.
+    pub fn size(&self) -> usize {
+        match self {
+            Tree::Agent { aux, .. } => 1 + aux.iter().map(Tree::size).sum::<usize>(),
+            Tree::Var { .. } => 0,
+        }
+    }
+    /// Compares agents and structure, treating any two variables as equal.
+    pub fn same_shape(&self, other: &Tree) -> bool {
+        match (self, other) {
+            (Tree::Agent { id: a, aux: x }, Tree::Agent { id: b, aux: y }) => {
+                a == b && x.len() == y.len() && x.iter().zip(y).all(|(x, y)| x.same_shape(y))
+            }
+            (Tree::Var { .. }, Tree::Var { .. }) => true,
+            _ => false,
+        }
+    }
+    /// The subtree reached by following `path`, where each index picks an aux
+    /// port of the agent reached so far. The empty path is the tree itself.
+    pub fn get_path(&self, path: &[usize]) -> Option<&Tree> {
+        match path.split_first() {
+            None => Some(self),
+            Some((i, rest)) => match self {
+                Tree::Agent { aux, .. } => aux.get(*i)?.get_path(rest),
+                Tree::Var { .. } => None,
+            },
+        }
+    }
+    /// Mutable version of [`Tree::get_path`].
+    pub fn get_path_mut(&mut self, path: &[usize]) -> Option<&mut Tree> {
+        match path.split_first() {
+            None => Some(self),
+            Some((i, rest)) => match self {
+                Tree::Agent { aux, .. } => aux.get_mut(*i)?.get_path_mut(rest),
+                Tree::Var { .. } => None,
+            },
+        }
+    }
+    /// Rewrites variable ids according to `map`, leaving unmapped ones as they are.
+    /// Unlike `Net::freshen`, this never allocates new variables.
+    pub fn rename_vars(&self, map: &BTreeMap<VarId, VarId>) -> Tree {
+        match self {
+            Tree::Agent { id, aux } => Tree::Agent {
+                id: *id,
+                aux: aux.iter().map(|x| x.rename_vars(map)).collect(),
+            },
+            Tree::Var { id } => Tree::Var {
+                id: *map.get(id).unwrap_or(id),
+            },
+        }
+    }
+    /// Rewrites agent ids with `f`, leaving variables as they are.
+    pub fn map_agents(&self, f: &dyn Fn(AgentId) -> AgentId) -> Tree {
+        match self {
+            Tree::Agent { id, aux } => Tree::Agent {
+                id: f(*id),
+                aux: aux.iter().map(|x| x.map_agents(f)).collect(),
+            },
+            Tree::Var { id } => Tree::Var { id: *id },
+        }
+    }
+    /// Whether no variable is left in the tree once bindings in `net` are
+    /// resolved. Variables bound in a cycle count as unbound.
+    pub fn is_ground(&self, net: &Net) -> bool {
+        fn visit(tree: &Tree) -> bool {
+            match tree {
+                Tree::Agent { aux, .. } => aux.iter().all(visit),
+                Tree::Var { .. } => false,
+            }
+        }
+        visit(&net.substitute_ref(self))
+    }
+}
+
 #[derive(Debug)]
 pub struct InteractionRule {
     pub left_ports: Vec<Tree>,
     pub right_ports: Vec<Tree>,
+    /// Active pairs created by the rule, sharing variables with the ports.
+    pub body: Vec<(Tree, Tree)>,
+}
+
+impl InteractionRule {
+    /// Whether both rules are the same up to renaming of variables. With
+    /// `flip`, `other` is read with its sides swapped.
+    fn alpha_eq(&self, other: &InteractionRule, flip: bool) -> bool {
+        fn visit(
+            a: &Tree,
+            b: &Tree,
+            map: &mut BTreeMap<VarId, VarId>,
+            back: &mut BTreeMap<VarId, VarId>,
+        ) -> bool {
+            match (a, b) {
+                (Tree::Agent { id: x, aux: y }, Tree::Agent { id: z, aux: w }) => {
+                    x == z
+                        && y.len() == w.len()
+                        && y.iter().zip(w).all(|(y, w)| visit(y, w, map, back))
+                }
+                (Tree::Var { id: x }, Tree::Var { id: y }) => {
+                    *map.entry(*x).or_insert(*y) == *y && *back.entry(*y).or_insert(*x) == *x
+                }
+                _ => false,
+            }
+        }
+        let (left, right) = if flip {
+            (&other.right_ports, &other.left_ports)
+        } else {
+            (&other.left_ports, &other.right_ports)
+        };
+        if self.left_ports.len() != left.len()
+            || self.right_ports.len() != right.len()
+            || self.body.len() != other.body.len()
+        {
+            return false;
+        }
+        let ours = self
+            .left_ports
+            .iter()
+            .chain(&self.right_ports)
+            .chain(self.body.iter().flat_map(|(a, b)| [a, b]));
+        let theirs = left
+            .iter()
+            .chain(right)
+            .chain(other.body.iter().flat_map(|(a, b)| [a, b]));
+        let (mut map, mut back) = (BTreeMap::new(), BTreeMap::new());
+        ours.zip(theirs)
+            .all(|(a, b)| visit(a, b, &mut map, &mut back))
+    }
 }
 
 #[derive(Debug, Default)]
@@ -21,21 +184,378 @@ pub struct InteractionSystem {
     pub rules: BTreeMap<AgentId, BTreeMap<AgentId, InteractionRule>>,
 }
 
+impl InteractionSystem {
+    /// The rule with `a` on the left and `b` on the right, if there is one.
+    pub fn get_rule(&self, a: AgentId, b: AgentId) -> Option<&InteractionRule> {
+        self.rules.get(&a).and_then(|x| x.get(&b))
+    }
+    /// Agents that take part in some rule, in id order, and a matrix whose
+    /// cell `[i][j]` is set when there is a rule with agent `i` on the left
+    /// and agent `j` on the right.
+    pub fn adjacency(&self) -> (Vec<AgentId>, Vec<Vec<bool>>) {
+        let agents: Vec<AgentId> = self
+            .rules
+            .iter()
+            .flat_map(|(a, m)| std::iter::once(*a).chain(m.keys().copied()))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let matrix = agents
+            .iter()
+            .map(|a| {
+                agents
+                    .iter()
+                    .map(|b| self.rules.get(a).is_some_and(|m| m.contains_key(b)))
+                    .collect()
+            })
+            .collect();
+        (agents, matrix)
+    }
+    /// Renders [`InteractionSystem::adjacency`] as CSV, with a header row and
+    /// column of agent names and `1`/`0` cells.
+    pub fn adjacency_csv(&self, show_agent: &dyn Fn(AgentId) -> String) -> String {
+        let (agents, matrix) = self.adjacency();
+        let names: Vec<String> = agents.iter().map(|x| show_agent(*x)).collect();
+        let mut out = String::new();
+        for name in &names {
+            out.push(',');
+            out.push_str(name);
+        }
+        out.push('\n');
+        for (name, row) in names.iter().zip(matrix) {
+            out.push_str(name);
+            for cell in row {
+                out.push_str(if cell { ",1" } else { ",0" });
+            }
+            out.push('\n');
+        }
+        out
+    }
+    /// Adds the rules of `other`. A rule both systems have, in either
+    /// orientation, is kept once if it is the same up to renaming of
+    /// variables. Otherwise its pair, as `other` orients it, is a conflict.
+    /// Both systems have to use the same agent ids.
+    pub fn union(
+        mut self,
+        other: InteractionSystem,
+    ) -> Result<InteractionSystem, Vec<(AgentId, AgentId)>> {
+        let mut conflicts = vec![];
+        for (a, rules) in other.rules {
+            for (b, rule) in rules {
+                let same = if let Some(ours) = self.get_rule(a, b) {
+                    ours.alpha_eq(&rule, false)
+                } else if let Some(ours) = self.get_rule(b, a) {
+                    ours.alpha_eq(&rule, true)
+                } else {
+                    self.rules.entry(a).or_default().insert(b, rule);
+                    continue;
+                };
+                if !same {
+                    conflicts.push((a, b));
+                }
+            }
+        }
+        if conflicts.is_empty() {
+            Ok(self)
+        } else {
+            Err(conflicts)
+        }
+    }
+    /// Reports agent pairs that have a rule in both orientations, since only
+    /// one of them would ever be used by `interact`.
+    pub fn check_deterministic(&self) -> Result<(), Vec<(AgentId, AgentId)>> {
+        let conflicts: Vec<_> = self
+            .rules
+            .iter()
+            .flat_map(|(a, m)| m.keys().map(move |b| (*a, *b)))
+            .filter(|(a, b)| a < b && self.rules.get(b).is_some_and(|x| x.contains_key(a)))
+            .collect();
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(conflicts)
+        }
+    }
+}
+
+/// A structural problem found by [`Net::validate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WiringError {
+    /// A variable doesn't occur exactly twice, counting its binding as one use.
+    UseCount { var: VarId, uses: usize },
+    /// A variable that has no slot in `vars`.
+    MissingVar(VarId),
+    /// A variable whose binding contains the variable itself.
+    Cycle(VarId),
+    /// An agent used with a different number of ports than elsewhere.
+    ArityMismatch {
+        agent: AgentId,
+        expected: usize,
+        found: usize,
+    },
+}
+
+/// How a bounded reduction came to a halt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    /// No interactions were left to reduce.
+    Normal,
+    /// The net stopped changing structurally between two fingerprints.
+    FixedPoint,
+}
+
+/// What a single step of [`Net::step_diff`] did.
+#[derive(Clone, Debug)]
+pub struct StepDiff {
+    /// The active pair that was reduced.
+    pub consumed: (Tree, Tree),
+    /// The pairs that reducing it queued, in the order they were queued.
+    pub added: Vec<(Tree, Tree)>,
+}
+
+/// Summary of a net, as computed by [`Net::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReductionStats {
+    /// Live agents in the pending and stuck pairs, following bindings.
+    pub agents: usize,
+    /// Distinct variables those pairs reach that aren't bound to a tree.
+    pub unresolved_vars: usize,
+    pub pending: usize,
+    pub stuck: usize,
+}
+
+/// Normal forms found by [`Net::explore_normal_forms_bounded`].
+#[derive(Clone, Debug, Default)]
+pub struct Exploration {
+    pub normal_forms: Vec<Net>,
+    /// Whether every reachable state was visited, so there are no other normal forms.
+    pub complete: bool,
+}
+
+/// Same as [`Net::from_syntax`], without the variable names.
+impl<F: Fn(&str) -> Option<AgentId>> TryFrom<(syntax::Net, F)> for Net {
+    type Error = String;
+    fn try_from((net, agent): (syntax::Net, F)) -> Result<Self, Self::Error> {
+        Net::from_syntax(net, &agent).map(|x| x.0)
+    }
+}
+
+/// Error raised while reducing a net.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetError {
+    /// `agent` met `other` with a different number of aux ports than the rule
+    /// between them expects.
+    ArityMismatch {
+        agent: AgentId,
+        other: AgentId,
+        expected: usize,
+        found: usize,
+    },
+    /// Pair number `step` of a trace given to [`Net::replay`] wasn't pending
+    /// when its turn came.
+    NotPending { step: usize },
+    /// There is no rule for this pair, and the net is `strict`.
+    Stuck(Tree, Tree),
+    /// The net grew past its `size_limit`, which this is.
+    SizeLimit(usize),
+}
+
+impl std::fmt::Display for NetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetError::ArityMismatch {
+                agent,
+                other,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Agent {:?} has {} aux ports when interacting with {:?}, but the rule expects {}",
+                agent.data(),
+                found,
+                other.data(),
+                expected
+            ),
+            NetError::NotPending { step } => {
+                write!(f, "Pair {} of the trace is not a pending interaction", step)
+            }
+            NetError::Stuck(a, b) => write!(f, "No rule for the pair {} ~ {}", a, b),
+            NetError::SizeLimit(limit) => write!(f, "The net grew past {} agents", limit),
+        }
+    }
+}
+
+/// How [`Net::show_net`] prints the variables of a net.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShowMode {
+    /// Variables by name, with their bindings listed separately.
+    Raw,
+    /// Bound variables replaced by the trees they are bound to.
+    Resolved,
+    /// Like `Raw`, but with variables numbered as wires `#n`, followed by the
+    /// two port positions each wire connects.
+    Wires,
+}
+
+/// When applying a rule links its ports to the ports of the agents it
+/// replaces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReductionStrategy {
+    /// Every port is linked as soon as the rule is applied.
+    #[default]
+    Eager,
+    /// Ports that connect two agents are linked right away, since they form
+    /// new active pairs. Links involving a variable are queued behind every
+    /// other pending interaction, and only resolved once nothing else is left.
+    Lazy,
+}
+
+/// Saved reduction state of a [`Net`], taken by [`Net::checkpoint`].
+#[derive(Clone, Debug)]
+pub struct NetCheckpoint {
+    interactions: Vec<(Tree, Tree)>,
+    deferred: Vec<(Tree, Tree)>,
+    vars: SlotMap<VarId, Option<Tree>>,
+    stuck: Vec<(Tree, Tree)>,
+    growth: isize,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Net {
     pub interactions: Vec<(Tree, Tree)>,
     pub vars: SlotMap<VarId, Option<Tree>>,
     pub stuck: Vec<(Tree, Tree)>,
     pub system: Rc<InteractionSystem>,
+    /// When set, a pair with no rule has its aux ports linked pairwise
+    /// instead of getting stuck, as long as both agents have the same arity.
+    pub pass_through: bool,
+    /// When set, a pair with no rule is an error instead of getting stuck.
+    /// Frozen pairs still get stuck.
+    pub strict: bool,
+    /// Agent used to freeze subtrees, as `Frozen(tree)`. Pairs involving it
+    /// always get stuck, whatever the rules say, until [`Net::thaw`] is called.
+    pub frozen: Option<AgentId>,
+    pub strategy: ReductionStrategy,
+    /// Links the lazy strategy held back, oldest first. They only join
+    /// `interactions` once it runs out.
+    pub deferred: Vec<(Tree, Tree)>,
+    /// When set, [`Net::normal`] fails once the net has more agents than this.
+    pub size_limit: Option<usize>,
+    /// Agents the rules applied so far created, minus the ones they consumed.
+    /// Keeps track of [`Net::size`] without counting again after every step.
+    pub growth: isize,
+    /// Priority of each agent, 0 if missing. A pair has the priority of its
+    /// highest agent, and [`Net::step`] reduces the highest pair first, and
+    /// the most recently pushed one among equals.
+    pub priorities: BTreeMap<AgentId, u8>,
+    /// Pairs queued so far by the step [`Net::step_diff`] is taking.
+    pub(crate) recording: Option<Vec<(Tree, Tree)>>,
 }
 
 impl Net {
+    /// Lowers a syntax net, looking agents up by name with `agent` and
+    /// allocating a fresh variable for each variable name. `with` redexes
+    /// become pending interactions. Also returns the variable each name got.
+    pub fn from_syntax(
+        net: syntax::Net,
+        agent: &dyn Fn(&str) -> Option<AgentId>,
+    ) -> Result<(Net, BTreeMap<String, VarId>), String> {
+        fn load_tree(
+            tree: syntax::Tree,
+            agent: &dyn Fn(&str) -> Option<AgentId>,
+            vars: &mut BTreeMap<String, VarId>,
+            net: &mut Net,
+        ) -> Result<Tree, String> {
+            Ok(match tree {
+                syntax::Tree::Agent { name, aux, .. } => Tree::Agent {
+                    id: agent(&name).ok_or_else(|| format!("Unknown agent {}", name))?,
+                    aux: aux
+                        .into_iter()
+                        .map(|x| load_tree(x, agent, vars, net))
+                        .collect::<Result<_, _>>()?,
+                },
+                syntax::Tree::Variable { .. } | syntax::Tree::Port(_) => Tree::Var {
+                    id: *vars
+                        .entry(tree.var_name().unwrap())
+                        .or_insert_with(|| net.new_var()),
+                },
+                syntax::Tree::With { rest, redex } => {
+                    let t0 = load_tree(redex.0, agent, vars, net)?;
+                    let t1 = load_tree(redex.1, agent, vars, net)?;
+                    net.interactions.push((t0, t1));
+                    load_tree(*rest, agent, vars, net)?
+                }
+            })
+        }
+        let mut vars = BTreeMap::new();
+        let mut res = Net::default();
+        for (a, b) in net.interactions {
+            let a = load_tree(a, agent, &mut vars, &mut res)?;
+            let b = load_tree(b, agent, &mut vars, &mut res)?;
+            res.interactions.push((a, b));
+        }
+        Ok((res, vars))
+    }
+    /// A net with `pairs` pending under `system`, and `var_count` unbound
+    /// variables. Variables in `pairs` have to be among the first
+    /// `var_count` ids a fresh net allocates with [`Net::new_var`].
+    pub fn from_interactions(
+        pairs: Vec<(Tree, Tree)>,
+        system: Rc<InteractionSystem>,
+        var_count: usize,
+    ) -> Net {
+        let mut net = Net {
+            interactions: pairs,
+            system,
+            ..Default::default()
+        };
+        for _ in 0..var_count {
+            net.new_var();
+        }
+        net
+    }
     pub fn new_var(&mut self) -> VarId {
         self.vars.insert(None)
     }
+    /// Captures the reduction state: everything reducing changes, `growth`
+    /// included, but not settings like the rules or the strategy. The whole
+    /// `vars` slotmap is kept, including its free slots, so ids allocated
+    /// after a restore are the same ones that were allocated after the
+    /// checkpoint was taken.
+    pub fn checkpoint(&self) -> NetCheckpoint {
+        NetCheckpoint {
+            interactions: self.interactions.clone(),
+            deferred: self.deferred.clone(),
+            vars: self.vars.clone(),
+            stuck: self.stuck.clone(),
+            growth: self.growth,
+        }
+    }
+    pub fn restore(&mut self, checkpoint: NetCheckpoint) {
+        self.interactions = checkpoint.interactions;
+        self.deferred = checkpoint.deferred;
+        self.vars = checkpoint.vars;
+        self.stuck = checkpoint.stuck;
+        self.growth = checkpoint.growth;
+    }
     fn link(&mut self, a: Tree, b: Tree) {
+        if let Some(added) = &mut self.recording {
+            added.push((a.clone(), b.clone()));
+        }
         self.interactions.push((a, b))
     }
+    /// Links a rule port to the port it replaces, following `self.strategy`.
+    fn link_port(&mut self, a: Tree, b: Tree) {
+        let principal = matches!((&a, &b), (Tree::Agent { .. }, Tree::Agent { .. }));
+        if self.strategy == ReductionStrategy::Lazy && !principal {
+            if let Some(added) = &mut self.recording {
+                added.push((a.clone(), b.clone()));
+            }
+            self.deferred.push((a, b))
+        } else {
+            self.link(a, b)
+        }
+    }
     fn freshen(&mut self, scope: &mut BTreeMap<VarId, VarId>, tree: &Tree) -> Tree {
         use Tree::*;
         match tree {
@@ -53,7 +573,37 @@ impl Net {
             },
         }
     }
-    fn apply_rule(&mut self, rule: &InteractionRule, left: Vec<Tree>, right: Vec<Tree>) {
+    fn apply_rule(
+        &mut self,
+        rule: &InteractionRule,
+        (left_id, left): (AgentId, Vec<Tree>),
+        (right_id, right): (AgentId, Vec<Tree>),
+    ) -> Result<(), NetError> {
+        for (agent, other, expected, found) in [
+            (left_id, right_id, rule.left_ports.len(), left.len()),
+            (right_id, left_id, rule.right_ports.len(), right.len()),
+        ] {
+            if expected != found {
+                return Err(NetError::ArityMismatch {
+                    agent,
+                    other,
+                    expected,
+                    found,
+                });
+            }
+        }
+        let created = rule
+            .left_ports
+            .iter()
+            .chain(&rule.right_ports)
+            .map(Tree::size);
+        let created = created.sum::<usize>()
+            + rule
+                .body
+                .iter()
+                .map(|(a, b)| a.size() + b.size())
+                .sum::<usize>();
+        self.growth += created as isize - 2;
         let mut var_set = BTreeMap::new();
         for (i, j) in rule
             .left_ports
@@ -62,24 +612,55 @@ impl Net {
             .chain(rule.right_ports.iter().zip(right.into_iter()))
         {
             let i = self.freshen(&mut var_set, i);
-            self.link(i, j);
+            self.link_port(i, j);
         }
+        for (a, b) in &rule.body {
+            let a = self.freshen(&mut var_set, a);
+            let b = self.freshen(&mut var_set, b);
+            self.link(a, b);
+        }
+        Ok(())
+    }
+    pub fn interact(&mut self, a: Tree, b: Tree) -> Result<(), NetError> {
+        self.interact_with_stuck(a, b, &mut |_, _| ())
     }
-    pub fn interact(&mut self, a: Tree, b: Tree) {
+    /// Like `interact`, but calls `on_stuck` with the pair if it gets stuck.
+    pub fn interact_with_stuck(
+        &mut self,
+        a: Tree,
+        b: Tree,
+        on_stuck: &mut dyn FnMut(&Tree, &Tree),
+    ) -> Result<(), NetError> {
         use Tree::*;
         match (a, b) {
+            (Agent { id: id1, aux: aux1 }, Agent { id: id2, aux: aux2 })
+                if self.frozen.is_some_and(|x| x == id1 || x == id2) =>
+            {
+                let (a, b) = (Agent { id: id1, aux: aux1 }, Agent { id: id2, aux: aux2 });
+                on_stuck(&a, &b);
+                self.stuck.push((a, b));
+            }
             (Agent { id: id1, aux: aux1 }, Agent { id: id2, aux: aux2 }) => {
                 let rules = self.system.clone();
-                let rule = rules.rules.get(&id1).and_then(|x| x.get(&id2));
-                let rule_flip = rules.rules.get(&id2).and_then(|x| x.get(&id1));
+                let rule = rules.get_rule(id1, id2);
+                let rule_flip = rules.get_rule(id2, id1);
                 //println!("{:?} {:?} {:#?}", id1, id2, rules.rules);
                 if let Some(r) = rule {
-                    self.apply_rule(r, aux1, aux2);
+                    self.apply_rule(r, (id1, aux1), (id2, aux2))?;
                 } else if let Some(r) = rule_flip {
-                    self.apply_rule(r, aux2, aux1);
+                    self.apply_rule(r, (id2, aux2), (id1, aux1))?;
+                } else if self.pass_through && aux1.len() == aux2.len() {
+                    self.growth -= 2;
+                    for (a, b) in aux1.into_iter().zip(aux2) {
+                        self.link(a, b);
+                    }
                 } else {
-                    self.stuck
-                        .push((Agent { id: id1, aux: aux1 }, Agent { id: id2, aux: aux2 }));
+                    let (a, b) = (Agent { id: id1, aux: aux1 }, Agent { id: id2, aux: aux2 });
+                    if self.strict {
+                        return Err(NetError::Stuck(a, b));
+                    }
+                    on_stuck(&a, &b);
+                    self.stuck.push((a, b));
                 }
             }
             (a, Var { id }) | (Var { id }, a) => {
@@ -91,26 +672,923 @@ impl Net {
                 }
             }
         }
+        Ok(())
+    }
+    /// Unwraps every frozen tree that got stuck in an active pair and queues
+    /// it to interact with whatever it was facing. Frozen trees that haven't
+    /// reached an active pair yet stay frozen.
+    pub fn thaw(&mut self) {
+        let Some(frozen) = self.frozen else {
+            return;
+        };
+        let is_frozen =
+            |x: &Tree| matches!(x, Tree::Agent { id, aux } if *id == frozen && aux.len() == 1);
+        let mut stuck = vec![];
+        for (a, b) in std::mem::take(&mut self.stuck) {
+            let (a, b) = if is_frozen(&b) { (b, a) } else { (a, b) };
+            match a {
+                Tree::Agent { mut aux, .. } if is_frozen(&a) => {
+                    self.link(aux.pop().unwrap(), b);
+                }
+                a => stuck.push((a, b)),
+            }
+        }
+        self.stuck = stuck;
+    }
+    /// Drops a tree that is no longer connected to anything, freeing the slots
+    /// of its variables whose other end was already linked. Variables whose
+    /// other end is still live elsewhere in the net are left alone.
+    pub fn erase(&mut self, tree: Tree) {
+        match tree {
+            Tree::Agent { aux, .. } => aux.into_iter().for_each(|x| self.erase(x)),
+            Tree::Var { id } => {
+                if let Some(Some(_)) = self.vars.get(id)
+                    && let Some(Some(b)) = self.vars.remove(id)
+                {
+                    self.erase(b);
+                }
+            }
+        }
+    }
+    /// Removes the pending and stuck pairs and the variables that aren't
+    /// connected to any of `roots`, following both pairs and bindings.
+    pub fn gc_unreachable(&mut self, roots: &[VarId]) {
+        fn vars_of(tree: &Tree, out: &mut Vec<VarId>) {
+            match tree {
+                Tree::Agent { aux, .. } => aux.iter().for_each(|x| vars_of(x, out)),
+                Tree::Var { id } => out.push(*id),
+            }
+        }
+        let mut groups: Vec<Vec<VarId>> = vec![];
+        for (a, b) in self.interactions.iter().chain(self.stuck.iter()) {
+            let mut vars = vec![];
+            vars_of(a, &mut vars);
+            vars_of(b, &mut vars);
+            groups.push(vars);
+        }
+        for (id, b) in &self.vars {
+            if let Some(b) = b {
+                let mut vars = vec![id];
+                vars_of(b, &mut vars);
+                groups.push(vars);
+            }
+        }
+        let mut reachable: BTreeSet<VarId> = roots.iter().copied().collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for vars in &groups {
+                if vars.iter().any(|x| reachable.contains(x)) {
+                    for x in vars {
+                        changed |= reachable.insert(*x);
+                    }
+                }
+            }
+        }
+        let live = |(a, b): &(Tree, Tree)| {
+            let mut vars = vec![];
+            vars_of(a, &mut vars);
+            vars_of(b, &mut vars);
+            vars.iter().any(|x| reachable.contains(x))
+        };
+        self.interactions.retain(live);
+        self.stuck.retain(live);
+        self.vars.retain(|id, _| reachable.contains(&id));
+    }
+    /// Number of agents in the pending, deferred and stuck pairs and in the
+    /// variable bindings. Agent explosions show up here long before they
+    /// exhaust a step limit.
+    pub fn size(&self) -> usize {
+        let pairs = self
+            .interactions
+            .iter()
+            .chain(&self.deferred)
+            .chain(&self.stuck);
+        let pairs = pairs.map(|(a, b)| a.size() + b.size()).sum::<usize>();
+        pairs + self.vars.values().flatten().map(Tree::size).sum::<usize>()
+    }
+    /// Counts what is left of the net, typically after [`Net::normal`].
+    pub fn stats(&self) -> ReductionStats {
+        fn visit(net: &Net, tree: &Tree, agents: &mut usize, vars: &mut BTreeSet<VarId>) {
+            match tree {
+                Tree::Agent { aux, .. } => {
+                    *agents += 1;
+                    aux.iter().for_each(|x| visit(net, x, agents, vars));
+                }
+                Tree::Var { id } => match net.vars.get(*id) {
+                    Some(Some(b)) if !vars.contains(id) => {
+                        // Marked as seen so that cyclic bindings terminate.
+                        vars.insert(*id);
+                        visit(net, b, agents, vars);
+                    }
+                    _ => {
+                        vars.insert(*id);
+                    }
+                },
+            }
+        }
+        let mut agents = 0;
+        let mut vars = BTreeSet::new();
+        for (a, b) in self.interactions.iter().chain(self.stuck.iter()) {
+            visit(self, a, &mut agents, &mut vars);
+            visit(self, b, &mut agents, &mut vars);
+        }
+        ReductionStats {
+            agents,
+            unresolved_vars: vars
+                .iter()
+                .filter(|x| !matches!(self.vars.get(**x), Some(Some(_))))
+                .count(),
+            pending: self.interactions.len(),
+            stuck: self.stuck.len(),
+        }
+    }
+    /// Finds every subtree of the pending and stuck pairs that matches `pattern`,
+    /// following variable bindings. Variables in the pattern are holes; each
+    /// match maps them to the subtree they stand for. A hole used twice has to
+    /// stand for the same subtree both times.
+    pub fn find_matches(&self, pattern: &Tree) -> Vec<BTreeMap<VarId, Tree>> {
+        fn same(a: &Tree, b: &Tree) -> bool {
+            match (a, b) {
+                (Tree::Agent { id: a, aux: x }, Tree::Agent { id: b, aux: y }) => {
+                    a == b && x.len() == y.len() && x.iter().zip(y).all(|(x, y)| same(x, y))
+                }
+                (Tree::Var { id: a }, Tree::Var { id: b }) => a == b,
+                _ => false,
+            }
+        }
+        fn unify(pattern: &Tree, tree: &Tree, holes: &mut BTreeMap<VarId, Tree>) -> bool {
+            match (pattern, tree) {
+                (Tree::Var { id }, _) => match holes.get(id) {
+                    Some(bound) => same(bound, tree),
+                    None => {
+                        holes.insert(*id, tree.clone());
+                        true
+                    }
+                },
+                (Tree::Agent { id: a, aux: x }, Tree::Agent { id: b, aux: y }) => {
+                    a == b && x.len() == y.len() && x.iter().zip(y).all(|(x, y)| unify(x, y, holes))
+                }
+                _ => false,
+            }
+        }
+        fn search(pattern: &Tree, tree: &Tree, out: &mut Vec<BTreeMap<VarId, Tree>>) {
+            let mut holes = BTreeMap::new();
+            if unify(pattern, tree, &mut holes) {
+                out.push(holes);
+            }
+            if let Tree::Agent { aux, .. } = tree {
+                aux.iter().for_each(|x| search(pattern, x, out));
+            }
+        }
+        let mut out = vec![];
+        for (a, b) in self.interactions.iter().chain(self.stuck.iter()) {
+            search(pattern, &self.substitute_ref(a), &mut out);
+            search(pattern, &self.substitute_ref(b), &mut out);
+        }
+        out
+    }
+    /// Counts the stuck pairs by the agents involved, with the smaller id first
+    /// so that `F ~ G` and `G ~ F` fall in the same group.
+    pub fn count_stuck_by_pair(&self) -> BTreeMap<(AgentId, AgentId), usize> {
+        let mut out = BTreeMap::new();
+        for (a, b) in &self.stuck {
+            if let (Tree::Agent { id: a, .. }, Tree::Agent { id: b, .. }) = (a, b) {
+                *out.entry((*a.min(b), *a.max(b))).or_default() += 1;
+            }
+        }
+        out
+    }
+    /// Counts the live agents of each kind in the pending and stuck pairs,
+    /// following variable bindings.
+    pub fn agent_histogram(&self) -> BTreeMap<AgentId, usize> {
+        fn visit(net: &Net, tree: &Tree, out: &mut BTreeMap<AgentId, usize>) {
+            match tree {
+                Tree::Agent { id, aux } => {
+                    *out.entry(*id).or_default() += 1;
+                    aux.iter().for_each(|x| visit(net, x, out));
+                }
+                Tree::Var { id } => {
+                    if let Some(Some(b)) = net.vars.get(*id) {
+                        visit(net, b, out);
+                    }
+                }
+            }
+        }
+        let mut out = BTreeMap::new();
+        for (a, b) in self.interactions.iter().chain(self.stuck.iter()) {
+            visit(self, a, &mut out);
+            visit(self, b, &mut out);
+        }
+        out
+    }
+    pub fn normal(&mut self) -> Result<(), NetError> {
+        let Some(limit) = self.size_limit else {
+            while self.step()? {}
+            return Ok(());
+        };
+        let start = self.size() as isize - self.growth;
+        loop {
+            if start + self.growth > limit as isize {
+                return Err(NetError::SizeLimit(limit));
+            }
+            if !self.step()? {
+                return Ok(());
+            }
+        }
+    }
+    /// Like `normal`, but calls `cb` with the number of steps taken so far and
+    /// the number of pending interactions every `every` steps.
+    pub fn normal_with_progress(
+        &mut self,
+        every: usize,
+        cb: &mut dyn FnMut(usize, usize),
+    ) -> Result<(), NetError> {
+        let mut steps = 0;
+        while self.step()? {
+            steps += 1;
+            if steps % every.max(1) == 0 {
+                cb(steps, self.interactions.len());
+            }
+        }
+        Ok(())
+    }
+    /// Like `normal`, but calls `on_stuck` with every pair as soon as it gets stuck.
+    pub fn normal_with_stuck(
+        &mut self,
+        on_stuck: &mut dyn FnMut(&Tree, &Tree),
+    ) -> Result<(), NetError> {
+        while let Some((a, b)) = self.next_pair() {
+            self.interact_with_stuck(a, b, on_stuck)?;
+        }
+        Ok(())
+    }
+    /// Performs the most recently pushed interaction among those with the
+    /// highest priority, see `priorities`. Returns `false` if there was
+    /// nothing left to reduce.
+    pub fn step(&mut self) -> Result<bool, NetError> {
+        if let Some((a, b)) = self.next_pair() {
+            self.interact(a, b)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+    /// Takes the pending pair to reduce next, following `self.priorities`.
+    fn next_pair(&mut self) -> Option<(Tree, Tree)> {
+        self.undefer();
+        if self.priorities.is_empty() {
+            return self.interactions.pop();
+        }
+        let priority = |x: &Tree| match x {
+            Tree::Agent { id, .. } => self.priorities.get(id).copied().unwrap_or(0),
+            Tree::Var { .. } => 0,
+        };
+        let index = self
+            .interactions
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, (a, b))| (priority(a).max(priority(b)), *i))
+            .map(|(i, _)| i)?;
+        Some(self.interactions.remove(index))
+    }
+    /// Queues the deferred links if nothing else is pending, so that the
+    /// oldest one is reduced first.
+    fn undefer(&mut self) {
+        if self.interactions.is_empty() {
+            self.interactions.extend(self.deferred.drain(..).rev());
+        }
+    }
+    /// Like `step`, but also returns the pair it reduced and the pairs that
+    /// reducing it queued. Returns `None` if there was nothing left to reduce.
+    pub fn step_diff(&mut self) -> Result<Option<StepDiff>, NetError> {
+        let Some((a, b)) = self.next_pair() else {
+            return Ok(None);
+        };
+        let consumed = (a.clone(), b.clone());
+        self.recording = Some(vec![]);
+        let res = self.interact(a, b);
+        let added = self.recording.take().unwrap_or_default();
+        res?;
+        Ok(Some(StepDiff { consumed, added }))
+    }
+    /// Reduces the pairs of `trace` in order, such as the `consumed` pairs
+    /// of a run of [`Net::step_diff`]. Variable ids have to match the ones
+    /// seen while recording, so `self` should be a copy of the net the trace
+    /// started from. Each pair may be pending in either orientation.
+    pub fn replay(&mut self, trace: &[(Tree, Tree)]) -> Result<(), NetError> {
+        for (step, (a, b)) in trace.iter().enumerate() {
+            let index = self
+                .interactions
+                .iter()
+                .position(|(x, y)| x == a && y == b || x == b && y == a)
+                .ok_or(NetError::NotPending { step })?;
+            let (a, b) = self.interactions.remove(index);
+            self.interact(a, b)?;
+        }
+        Ok(())
+    }
+    /// Replaces every occurrence of agent `from` with `to`, in pending and
+    /// stuck pairs as well as in the trees variables are bound to. Stuck
+    /// pairs stay stuck even if `to` has a rule for them.
+    pub fn replace_agent(&mut self, from: AgentId, to: AgentId) {
+        let f = |id| if id == from { to } else { id };
+        for (a, b) in self.interactions.iter_mut().chain(self.stuck.iter_mut()) {
+            *a = a.map_agents(&f);
+            *b = b.map_agents(&f);
+        }
+        for tree in self.vars.values_mut().flatten() {
+            *tree = tree.map_agents(&f);
+        }
+    }
+    /// Whether both nets have the same pending and stuck pairs, in the same
+    /// order, up to renaming of variables and with bindings resolved.
+    pub fn alpha_eq(&self, other: &Net) -> bool {
+        self.fingerprint() == other.fingerprint()
+    }
+    /// Reduces every pending interaction in every possible order, collecting
+    /// up to `max` normal forms, no two of which are [`Net::alpha_eq`].
+    /// Orders that run into a [`NetError`] are abandoned. This doesn't stop
+    /// on nets whose reduction keeps growing them without reaching `max`
+    /// normal forms; see [`Net::explore_normal_forms_bounded`] for those.
+    pub fn explore_normal_forms(&self, max: usize) -> Vec<Net> {
+        self.explore_normal_forms_bounded(max, usize::MAX)
+            .normal_forms
+    }
+    /// Like [`Net::explore_normal_forms`], but visits at most `state_limit`
+    /// distinct states, so a reduction that keeps growing the net still
+    /// stops, with whatever was found so far. States that only differ by
+    /// variable names and the order of their pairs are visited once.
+    pub fn explore_normal_forms_bounded(&self, max: usize, state_limit: usize) -> Exploration {
+        let mut work = self.clone();
+        let mut stack = vec![self.checkpoint()];
+        let mut seen = BTreeSet::new();
+        let mut normal_forms = vec![];
+        while let Some(state) = stack.pop() {
+            if normal_forms.len() == max || seen.len() == state_limit {
+                return Exploration {
+                    normal_forms,
+                    complete: false,
+                };
+            }
+            work.restore(state.clone());
+            work.undefer();
+            // Normal forms are states too, so each is only pushed once.
+            if !seen.insert(work.canonical_form()) {
+                continue;
+            }
+            if work.interactions.is_empty() {
+                if !normal_forms.iter().any(|x: &Net| x.alpha_eq(&work)) {
+                    normal_forms.push(work.clone());
+                }
+                continue;
+            }
+            for i in 0..work.interactions.len() {
+                work.restore(state.clone());
+                work.undefer();
+                let pair = work.interactions.remove(i);
+                work.interactions.push(pair);
+                if work.step().is_ok() {
+                    stack.push(work.checkpoint());
+                }
+            }
+        }
+        Exploration {
+            normal_forms,
+            complete: true,
+        }
+    }
+    /// Description of the pending and stuck pairs, with bindings resolved,
+    /// that doesn't depend on variable ids, on the order of the pairs or on
+    /// which side of a pair comes first.
+    ///
+    /// Each side of a pair is first labelled by its shape. Labels are then
+    /// refined by the labels of the sides its variables lead to, until no
+    /// label splits any further, and pairs are sorted by the labels of their
+    /// sides before variables are numbered. Sides that refinement can't tell
+    /// apart are taken to be interchangeable, which only fails to hold in
+    /// highly symmetric nets.
+    pub fn canonical_form(&self) -> String {
+        fn shape(tree: &Tree, out: &mut String) {
+            match tree {
+                Tree::Agent { id, aux } => {
+                    out.push_str(&format!("{:?}(", id.data()));
+                    aux.iter().for_each(|x| shape(x, out));
+                    out.push(')');
+                }
+                Tree::Var { .. } => out.push('_'),
+            }
+        }
+        fn vars(tree: &Tree, out: &mut Vec<VarId>) {
+            match tree {
+                Tree::Agent { aux, .. } => aux.iter().for_each(|x| vars(x, out)),
+                Tree::Var { id } => out.push(*id),
+            }
+        }
+        /// Replaces each key by its position among the distinct keys, sorted.
+        fn rank<K: Ord>(keys: Vec<K>) -> (Vec<usize>, usize) {
+            let distinct: BTreeSet<&K> = keys.iter().collect();
+            let distinct: Vec<&K> = distinct.into_iter().collect();
+            let ranks = keys
+                .iter()
+                .map(|x| distinct.binary_search(&x).unwrap())
+                .collect();
+            (ranks, distinct.len())
+        }
+        // Sides `2 * i` and `2 * i + 1` belong to the `i`th pair, stuck pairs last.
+        let sides: Vec<Tree> = self
+            .interactions
+            .iter()
+            .chain(self.stuck.iter())
+            .flat_map(|(a, b)| [self.substitute_ref(a), self.substitute_ref(b)])
+            .collect();
+        let stuck_from = 2 * self.interactions.len();
+        let occurrences: Vec<Vec<VarId>> = sides
+            .iter()
+            .map(|x| {
+                let mut out = vec![];
+                vars(x, &mut out);
+                out
+            })
+            .collect();
+        let mut places: BTreeMap<VarId, Vec<(usize, usize)>> = BTreeMap::new();
+        for (side, occurrences) in occurrences.iter().enumerate() {
+            for (index, var) in occurrences.iter().enumerate() {
+                places.entry(*var).or_default().push((side, index));
+            }
+        }
+        // The other place each variable occurrence leads to, if any.
+        let links: Vec<Vec<Option<(usize, usize)>>> = occurrences
+            .iter()
+            .enumerate()
+            .map(|(side, occurrences)| {
+                occurrences
+                    .iter()
+                    .enumerate()
+                    .map(|(index, var)| places[var].iter().find(|x| **x != (side, index)).copied())
+                    .collect()
+            })
+            .collect();
+        let (mut labels, mut count) = rank(
+            sides
+                .iter()
+                .enumerate()
+                .map(|(side, x)| {
+                    let mut out = String::new();
+                    shape(x, &mut out);
+                    (side >= stuck_from, out)
+                })
+                .collect(),
+        );
+        loop {
+            let (refined, refined_count) = rank(
+                links
+                    .iter()
+                    .enumerate()
+                    .map(|(side, links)| {
+                        let neighbours: Vec<_> = links
+                            .iter()
+                            .map(|x| {
+                                x.map(|(other, index)| {
+                                    // Whether it leads back into the same side,
+                                    // to the other side of the pair, or elsewhere.
+                                    let relation = if other == side {
+                                        0
+                                    } else if other == side ^ 1 {
+                                        1
+                                    } else {
+                                        2
+                                    };
+                                    (relation, labels[other], index)
+                                })
+                            })
+                            .collect();
+                        (labels[side], labels[side ^ 1], neighbours)
+                    })
+                    .collect(),
+            );
+            labels = refined;
+            if refined_count == count {
+                break;
+            }
+            count = refined_count;
+        }
+        let mut pairs: Vec<(usize, usize)> = (0..sides.len() / 2)
+            .map(|i| {
+                let (a, b) = (2 * i, 2 * i + 1);
+                if labels[b] < labels[a] {
+                    (b, a)
+                } else {
+                    (a, b)
+                }
+            })
+            .collect();
+        pairs.sort_by_key(|(a, b)| (labels[*a], labels[*b]));
+        let show_agent = |id: AgentId| format!("{:?}", id.data());
+        let mut show_var = var_namer();
+        let mut out = String::new();
+        let mut in_stuck = false;
+        for (a, b) in pairs {
+            if a >= stuck_from && !in_stuck {
+                in_stuck = true;
+                out.push_str("Stuck:\n");
+            }
+            out.push_str(&format!(
+                "{} ~ {}\n",
+                self.show_tree(&show_agent, &mut show_var, false, &sides[a]),
+                self.show_tree(&show_agent, &mut show_var, false, &sides[b])
+            ));
+        }
+        out
+    }
+    /// Hash of [`Net::canonical_form`], so it doesn't depend on variable ids
+    /// or on the order of pairs.
+    pub fn canonical_hash(&self) -> u64 {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+        let mut hasher = DefaultHasher::new();
+        self.canonical_form().hash(&mut hasher);
+        hasher.finish()
+    }
+    /// Checks that every variable is used exactly twice, that no variable is
+    /// bound to a tree containing itself, and that each agent always has the
+    /// same arity, both within the net and in the rules of its system.
+    pub fn validate(&self) -> Result<(), Vec<WiringError>> {
+        fn visit(
+            tree: &Tree,
+            uses: &mut BTreeMap<VarId, usize>,
+            arities: &mut BTreeMap<AgentId, usize>,
+            errors: &mut Vec<WiringError>,
+        ) {
+            match tree {
+                Tree::Agent { id, aux } => {
+                    let expected = *arities.entry(*id).or_insert(aux.len());
+                    if expected != aux.len() {
+                        errors.push(WiringError::ArityMismatch {
+                            agent: *id,
+                            expected,
+                            found: aux.len(),
+                        });
+                    }
+                    aux.iter().for_each(|x| visit(x, uses, arities, errors));
+                }
+                Tree::Var { id } => *uses.entry(*id).or_default() += 1,
+            }
+        }
+        let mut errors = vec![];
+        let mut uses = BTreeMap::new();
+        let mut arities = BTreeMap::new();
+        for (a, m) in &self.system.rules {
+            for (b, rule) in m {
+                arities.entry(*a).or_insert(rule.left_ports.len());
+                arities.entry(*b).or_insert(rule.right_ports.len());
+                // Bodies only contribute arities; their variables belong to the rule.
+                for tree in rule.body.iter().flat_map(|(l, r)| [l, r]) {
+                    visit(tree, &mut BTreeMap::new(), &mut arities, &mut vec![]);
+                }
+            }
+        }
+        for (a, b) in self.interactions.iter().chain(self.stuck.iter()) {
+            visit(a, &mut uses, &mut arities, &mut errors);
+            visit(b, &mut uses, &mut arities, &mut errors);
+        }
+        for (id, b) in &self.vars {
+            if let Some(b) = b {
+                *uses.entry(id).or_default() += 1;
+                visit(b, &mut uses, &mut arities, &mut errors);
+            }
+        }
+        for (var, uses) in uses {
+            if !self.vars.contains_key(var) {
+                errors.push(WiringError::MissingVar(var));
+            } else if uses != 2 {
+                errors.push(WiringError::UseCount { var, uses });
+            }
+        }
+        for (id, b) in &self.vars {
+            if b.is_some() && self.reaches_var(id, &Tree::Var { id }, &mut BTreeSet::new()) {
+                errors.push(WiringError::Cycle(id));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// Whether following bindings from `tree` leads back to `target`.
+    fn reaches_var(&self, target: VarId, tree: &Tree, seen: &mut BTreeSet<VarId>) -> bool {
+        match tree {
+            Tree::Agent { aux, .. } => aux.iter().any(|x| self.reaches_var(target, x, seen)),
+            Tree::Var { id } => {
+                if !seen.insert(*id) {
+                    return *id == target;
+                }
+                match self.vars.get(*id) {
+                    Some(Some(b)) => self.reaches_var(target, b, seen),
+                    _ => false,
+                }
+            }
+        }
+    }
+    /// Reduces the net, comparing its structure every `fingerprint_window` steps,
+    /// and stops early once two consecutive fingerprints are equal.
+    pub fn normal_until_stable(
+        &mut self,
+        fingerprint_window: usize,
+    ) -> Result<Termination, NetError> {
+        let mut last = None;
+        loop {
+            for _ in 0..fingerprint_window.max(1) {
+                if !self.step()? {
+                    return Ok(Termination::Normal);
+                }
+            }
+            let fingerprint = self.fingerprint();
+            if last.as_ref() == Some(&fingerprint) {
+                return Ok(Termination::FixedPoint);
+            }
+            last = Some(fingerprint);
+        }
+    }
+    /// Structural summary of the pending and stuck pairs, with bound variables
+    /// resolved and free variables named by order of appearance.
+    fn fingerprint(&self) -> Vec<String> {
+        let show_agent = |id: AgentId| format!("{:?}", id);
+        let mut show_var = var_namer();
+        let mut show_pair = |(a, b): &(Tree, Tree)| {
+            format!(
+                "{} ~ {}",
+                self.show_tree(&show_agent, &mut show_var, true, a),
+                self.show_tree(&show_agent, &mut show_var, true, b)
+            )
+        };
+        let mut fingerprint: Vec<String> = self.interactions.iter().map(&mut show_pair).collect();
+        fingerprint.push("Stuck:".to_string());
+        fingerprint.extend(self.stuck.iter().map(&mut show_pair));
+        fingerprint
+    }
+    /// Whether the net is a forest when agents and variables are taken as
+    /// nodes. Aux ports, variable occurrences, bindings and pairs are the
+    /// edges, so an agent with two ports wired together is a cycle too.
+    pub fn is_acyclic(&self) -> bool {
+        struct Graph {
+            parent: Vec<usize>,
+            vars: BTreeMap<VarId, usize>,
+            cyclic: bool,
+        }
+        impl Graph {
+            fn node(&mut self) -> usize {
+                self.parent.push(self.parent.len());
+                self.parent.len() - 1
+            }
+            fn find(&mut self, x: usize) -> usize {
+                if self.parent[x] != x {
+                    let root = self.find(self.parent[x]);
+                    self.parent[x] = root;
+                }
+                self.parent[x]
+            }
+            fn edge(&mut self, a: usize, b: usize) {
+                let (a, b) = (self.find(a), self.find(b));
+                if a == b {
+                    self.cyclic = true;
+                }
+                self.parent[a] = b;
+            }
+            /// Adds the nodes of `tree` and returns the one at its root.
+            fn tree(&mut self, tree: &Tree) -> usize {
+                match tree {
+                    Tree::Agent { aux, .. } => {
+                        let node = self.node();
+                        for x in aux {
+                            let child = self.tree(x);
+                            self.edge(node, child);
+                        }
+                        node
+                    }
+                    Tree::Var { id } => match self.vars.get(id) {
+                        Some(x) => *x,
+                        None => {
+                            let node = self.node();
+                            self.vars.insert(*id, node);
+                            node
+                        }
+                    },
+                }
+            }
+        }
+        let mut graph = Graph {
+            parent: vec![],
+            vars: BTreeMap::new(),
+            cyclic: false,
+        };
+        for (a, b) in self.interactions.iter().chain(self.stuck.iter()) {
+            let (a, b) = (graph.tree(a), graph.tree(b));
+            graph.edge(a, b);
+        }
+        for (id, b) in &self.vars {
+            if let Some(b) = b {
+                let (a, b) = (graph.tree(&Tree::Var { id }), graph.tree(b));
+                graph.edge(a, b);
+            }
+        }
+        !graph.cyclic
     }
-    pub fn normal(&mut self) {
-        while let Some((a, b)) = self.interactions.pop() {
-            self.interact(a, b)
+    /// Splits the net into parts that share no variables, each with its
+    /// pending and stuck pairs. Variables keep their ids, so the parts can
+    /// be reduced separately. Variables allocated while reducing them may
+    /// collide between parts, so rejoin them with [`Net::merge`].
+    pub fn connected_components(&self) -> Vec<Net> {
+        fn find(parent: &mut Vec<usize>, x: usize) -> usize {
+            if parent[x] != x {
+                let root = find(parent, parent[x]);
+                parent[x] = root;
+            }
+            parent[x]
+        }
+        fn vars_of(net: &Net, tree: &Tree, out: &mut BTreeSet<VarId>) {
+            match tree {
+                Tree::Agent { aux, .. } => aux.iter().for_each(|x| vars_of(net, x, out)),
+                Tree::Var { id } => {
+                    if out.insert(*id)
+                        && let Some(Some(b)) = net.vars.get(*id)
+                    {
+                        vars_of(net, b, out);
+                    }
+                }
+            }
+        }
+        let pairs: Vec<(bool, &(Tree, Tree))> = self
+            .interactions
+            .iter()
+            .map(|x| (false, x))
+            .chain(self.stuck.iter().map(|x| (true, x)))
+            .collect();
+        let mut parent: Vec<usize> = (0..pairs.len()).collect();
+        let mut owner: BTreeMap<VarId, usize> = BTreeMap::new();
+        let mut pair_vars = vec![];
+        for (i, (_, (a, b))) in pairs.iter().enumerate() {
+            let mut vars = BTreeSet::new();
+            vars_of(self, a, &mut vars);
+            vars_of(self, b, &mut vars);
+            for var in &vars {
+                let j = *owner.entry(*var).or_insert(i);
+                let (ri, rj) = (find(&mut parent, i), find(&mut parent, j));
+                parent[ri] = rj;
+            }
+            pair_vars.push(vars);
+        }
+        let mut components: BTreeMap<usize, (Net, BTreeSet<VarId>)> = BTreeMap::new();
+        for (i, ((is_stuck, pair), vars)) in pairs.into_iter().zip(pair_vars).enumerate() {
+            let root = find(&mut parent, i);
+            let (net, net_vars) = components.entry(root).or_insert_with(|| {
+                let net = Net {
+                    system: self.system.clone(),
+                    ..Default::default()
+                };
+                (net, BTreeSet::new())
+            });
+            if is_stuck {
+                net.stuck.push(pair.clone());
+            } else {
+                net.interactions.push(pair.clone());
+            }
+            net_vars.extend(vars);
+        }
+        components
+            .into_values()
+            .map(|(mut net, vars)| {
+                net.vars = self.vars.clone();
+                net.vars.retain(|id, _| vars.contains(&id));
+                net
+            })
+            .collect()
+    }
+    /// Moves the pairs and variables of `other` into this net. Variables of
+    /// `other` get fresh ids here, so the two nets may have been using the
+    /// same ones. Returns the id each variable of `other` was given.
+    pub fn merge(&mut self, other: Net) -> BTreeMap<VarId, VarId> {
+        let map: BTreeMap<VarId, VarId> = other.vars.keys().map(|x| (x, self.new_var())).collect();
+        for (old, new) in &map {
+            self.vars[*new] = other.vars[*old].as_ref().map(|x| x.rename_vars(&map));
+        }
+        let rename = |(a, b): (Tree, Tree)| (a.rename_vars(&map), b.rename_vars(&map));
+        self.interactions
+            .extend(other.interactions.into_iter().map(rename));
+        self.deferred.extend(other.deferred.into_iter().map(rename));
+        self.stuck.extend(other.stuck.into_iter().map(rename));
+        map
+    }
+    /// Rewrites the net so that every aux port is a variable. Each nested
+    /// agent is moved to a pair of its own, connected through a fresh
+    /// variable, and so are the trees variables are bound to. What is left
+    /// are pairs of two agents, of an agent and a variable, or of two variables.
+    /// Stuck pairs stay stuck, and the agents moved out of them become pending.
+    pub fn flatten(&mut self) {
+        fn visit(net: &mut Net, tree: Tree, out: &mut Vec<(Tree, Tree)>) -> Tree {
+            match tree {
+                Tree::Agent { id, aux } => Tree::Agent {
+                    id,
+                    aux: aux
+                        .into_iter()
+                        .map(|x| match x {
+                            Tree::Agent { .. } => {
+                                let var = net.new_var();
+                                let x = visit(net, x, out);
+                                out.push((Tree::Var { id: var }, x));
+                                Tree::Var { id: var }
+                            }
+                            Tree::Var { .. } => x,
+                        })
+                        .collect(),
+                },
+                Tree::Var { .. } => tree,
+            }
+        }
+        let mut pairs = std::mem::take(&mut self.interactions);
+        for (id, binding) in self.vars.iter_mut() {
+            if let Some(tree) = binding.take() {
+                pairs.push((Tree::Var { id }, tree));
+            }
+        }
+        for (a, b) in pairs {
+            let mut out = vec![];
+            let a = visit(self, a, &mut out);
+            let b = visit(self, b, &mut out);
+            self.interactions.push((a, b));
+            self.interactions.extend(out);
+        }
+        let mut out = vec![];
+        for (a, b) in std::mem::take(&mut self.stuck) {
+            let a = visit(self, a, &mut out);
+            let b = visit(self, b, &mut out);
+            self.stuck.push((a, b));
+        }
+        self.interactions.extend(out);
+    }
+    /// Reallocates every variable so that ids follow the order in which they
+    /// are first reached from `interactions` and then `stuck`. Two alpha-equivalent
+    /// nets built in the same order end up with identical ids.
+    pub fn canonicalize_vars(&mut self) {
+        fn visit(net: &Net, tree: &Tree, seen: &mut BTreeSet<VarId>, order: &mut Vec<VarId>) {
+            match tree {
+                Tree::Agent { aux, .. } => aux.iter().for_each(|x| visit(net, x, seen, order)),
+                Tree::Var { id } => {
+                    if seen.insert(*id) {
+                        order.push(*id);
+                        if let Some(Some(b)) = net.vars.get(*id) {
+                            visit(net, b, seen, order);
+                        }
+                    }
+                }
+            }
+        }
+        let mut seen = BTreeSet::new();
+        let mut order = vec![];
+        for (a, b) in self.interactions.iter().chain(self.stuck.iter()) {
+            visit(self, a, &mut seen, &mut order);
+            visit(self, b, &mut seen, &mut order);
+        }
+        order.extend(self.vars.keys().filter(|x| !seen.contains(x)));
+
+        let mut vars = SlotMap::with_key();
+        let map: BTreeMap<VarId, VarId> = order.iter().map(|x| (*x, vars.insert(None))).collect();
+        for (old, new) in &map {
+            vars[*new] = self.vars[*old].as_ref().map(|x| x.rename_vars(&map));
+        }
+        self.vars = vars;
+        for (a, b) in self.interactions.iter_mut().chain(self.stuck.iter_mut()) {
+            *a = a.rename_vars(&map);
+            *b = b.rename_vars(&map);
         }
     }
+    /// Renders the net. With `resolve` set, bound variables are inlined;
+    /// otherwise they are kept as names and their bindings are listed separately.
     pub fn show_net(
         &self,
         show_agent: &dyn Fn(AgentId) -> String,
-        scope: &mut BTreeMap<VarId, String>,
+        show_var: &mut dyn FnMut(VarId) -> String,
+        mode: ShowMode,
     ) -> String {
         use std::fmt::Write;
+        let mut wire_namer = {
+            let mut namer = var_namer();
+            move |id| format!("#{}", &namer(id)[1..])
+        };
+        let show_var: &mut dyn FnMut(VarId) -> String = if mode == ShowMode::Wires {
+            &mut wire_namer
+        } else {
+            show_var
+        };
+        let resolve = mode == ShowMode::Resolved;
         let mut s = String::new();
         writeln!(&mut s, "Interactions").unwrap();
         for (a, b) in &self.interactions {
             write!(
                 &mut s,
                 "\t{} ~ {}\n",
-                self.show_tree(show_agent, scope, &a),
-                self.show_tree(show_agent, scope, &b)
+                self.show_tree(show_agent, show_var, resolve, &a),
+                self.show_tree(show_agent, show_var, resolve, &b)
             )
             .unwrap();
         }
@@ -119,17 +1597,196 @@ impl Net {
             write!(
                 &mut s,
                 "\t{} ~ {}\n",
-                self.show_tree(show_agent, scope, &a),
-                self.show_tree(show_agent, scope, &b)
+                self.show_tree(show_agent, show_var, resolve, &a),
+                self.show_tree(show_agent, show_var, resolve, &b)
             )
             .unwrap();
         }
+        if !resolve {
+            writeln!(&mut s, "Vars:").unwrap();
+            for (id, b) in &self.vars {
+                if let Some(b) = b {
+                    let name = self.show_tree(show_agent, show_var, false, &Tree::Var { id });
+                    let b = self.show_tree(show_agent, show_var, false, b);
+                    writeln!(&mut s, "\t{} = {}", name, b).unwrap();
+                }
+            }
+        }
+        if mode == ShowMode::Wires {
+            writeln!(&mut s, "Wires:").unwrap();
+            for (id, ends) in self.wire_endpoints(show_agent) {
+                writeln!(&mut s, "\t{}: {}", show_var(id), ends.join(" -- ")).unwrap();
+            }
+        }
         s
     }
+    /// Renders the net as a Graphviz graph, following bindings. Active pairs
+    /// are bold edges, stuck ones dashed, and free variables become points.
+    pub fn to_dot(&self, show_agent: &dyn Fn(AgentId) -> String) -> String {
+        use std::fmt::Write;
+        // Returns the node standing for the root of `tree`, or the variable.
+        fn visit(
+            net: &Net,
+            tree: &Tree,
+            show_agent: &dyn Fn(AgentId) -> String,
+            nodes: &mut usize,
+            out: &mut String,
+            ends: &mut BTreeMap<VarId, Vec<(usize, usize)>>,
+        ) -> Result<usize, VarId> {
+            match tree {
+                Tree::Var { id } => match net.vars.get(*id) {
+                    Some(Some(b)) => visit(net, b, show_agent, nodes, out, ends),
+                    _ => Err(*id),
+                },
+                Tree::Agent { id, aux } => {
+                    let node = *nodes;
+                    *nodes += 1;
+                    writeln!(out, "  n{} [label={:?}];", node, show_agent(*id)).unwrap();
+                    for (i, x) in aux.iter().enumerate() {
+                        match visit(net, x, show_agent, nodes, out, ends) {
+                            Ok(child) => writeln!(
+                                out,
+                                "  n{} -- n{} [taillabel=\"{}\"];",
+                                node,
+                                child,
+                                i + 1
+                            )
+                            .unwrap(),
+                            Err(var) => ends.entry(var).or_default().push((node, i + 1)),
+                        }
+                    }
+                    Ok(node)
+                }
+            }
+        }
+        fn collect_vars(tree: &Tree, out: &mut BTreeSet<VarId>) {
+            match tree {
+                Tree::Var { id } => {
+                    out.insert(*id);
+                }
+                Tree::Agent { aux, .. } => aux.iter().for_each(|x| collect_vars(x, out)),
+            }
+        }
+        let mut out = String::from("graph net {\n");
+        let mut nodes = 0;
+        let mut ends = BTreeMap::new();
+        for (pairs, style) in [(&self.interactions, "bold"), (&self.stuck, "dashed")] {
+            for (a, b) in pairs {
+                let a = visit(self, a, show_agent, &mut nodes, &mut out, &mut ends);
+                let b = visit(self, b, show_agent, &mut nodes, &mut out, &mut ends);
+                match (a, b) {
+                    (Ok(a), Ok(b)) => {
+                        writeln!(out, "  n{} -- n{} [style={}];", a, b, style).unwrap()
+                    }
+                    (Ok(node), Err(var)) | (Err(var), Ok(node)) => {
+                        ends.entry(var).or_default().push((node, 0))
+                    }
+                    (Err(a), Err(b)) => {
+                        // Two wires joined directly; give the joint a node of its own.
+                        writeln!(out, "  n{} [shape=point];", nodes).unwrap();
+                        ends.entry(a).or_default().push((nodes, 0));
+                        ends.entry(b).or_default().push((nodes, 0));
+                        nodes += 1;
+                    }
+                }
+            }
+        }
+        // Bindings that nothing refers to anymore are results sitting on free
+        // ports, like the output of a fully reduced net.
+        let mut referenced = BTreeSet::new();
+        let trees = self
+            .interactions
+            .iter()
+            .chain(self.stuck.iter())
+            .flat_map(|(a, b)| [a, b]);
+        for tree in trees.chain(self.vars.values().flatten()) {
+            collect_vars(tree, &mut referenced);
+        }
+        for (var, b) in &self.vars {
+            if let Some(b) = b
+                && !referenced.contains(&var)
+            {
+                writeln!(out, "  v{:?} [shape=point];", var.data()).unwrap();
+                match visit(self, b, show_agent, &mut nodes, &mut out, &mut ends) {
+                    Ok(node) => writeln!(out, "  n{} -- v{:?};", node, var.data()).unwrap(),
+                    Err(other) => {
+                        writeln!(out, "  v{:?} -- v{:?};", other.data(), var.data()).unwrap()
+                    }
+                }
+            }
+        }
+        for (var, ends) in ends {
+            match ends.as_slice() {
+                [(a, i), (b, j)] => writeln!(
+                    out,
+                    "  n{} -- n{} [taillabel=\"{}\" headlabel=\"{}\"];",
+                    a, b, i, j
+                )
+                .unwrap(),
+                ends => {
+                    writeln!(out, "  v{:?} [shape=point];", var.data()).unwrap();
+                    for (node, i) in ends {
+                        writeln!(
+                            out,
+                            "  n{} -- v{:?} [taillabel=\"{}\"];",
+                            node,
+                            var.data(),
+                            i
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+    /// Where each variable is plugged in: `Agent.i` for aux port `i` (counting
+    /// from 1), `Agent.0` for the principal port of a tree it is bound to, and
+    /// the side of the pair when it sits at the root of one.
+    fn wire_endpoints(
+        &self,
+        show_agent: &dyn Fn(AgentId) -> String,
+    ) -> BTreeMap<VarId, Vec<String>> {
+        fn visit(
+            tree: &Tree,
+            at: String,
+            show_agent: &dyn Fn(AgentId) -> String,
+            out: &mut BTreeMap<VarId, Vec<String>>,
+        ) {
+            match tree {
+                Tree::Var { id } => out.entry(*id).or_default().push(at),
+                Tree::Agent { id, aux } => {
+                    for (i, x) in aux.iter().enumerate() {
+                        visit(x, format!("{}.{}", show_agent(*id), i + 1), show_agent, out);
+                    }
+                }
+            }
+        }
+        let mut out = BTreeMap::new();
+        for (kind, pairs) in [("pair", &self.interactions), ("stuck", &self.stuck)] {
+            for (i, (a, b)) in pairs.iter().enumerate() {
+                visit(a, format!("{} {} left", kind, i), show_agent, &mut out);
+                visit(b, format!("{} {} right", kind, i), show_agent, &mut out);
+            }
+        }
+        for (id, b) in &self.vars {
+            if let Some(b) = b {
+                let end = match b {
+                    Tree::Agent { id, .. } => format!("{}.0", show_agent(*id)),
+                    Tree::Var { .. } => "bound".to_string(),
+                };
+                out.entry(id).or_default().push(end);
+                visit(b, "bound".to_string(), show_agent, &mut out);
+            }
+        }
+        out
+    }
     pub fn show_tree(
         &self,
         show_agent: &dyn Fn(AgentId) -> String,
-        scope: &mut BTreeMap<VarId, String>,
+        show_var: &mut dyn FnMut(VarId) -> String,
+        resolve: bool,
         tree: &Tree,
     ) -> String {
         match tree {
@@ -140,36 +1797,55 @@ impl Net {
                 let mut i = aux.iter();
                 if let Some(e) = i.next() {
                     write!(&mut s, "(").unwrap();
-                    write!(&mut s, "{}", self.show_tree(show_agent, scope, e)).unwrap();
+                    write!(
+                        &mut s,
+                        "{}",
+                        self.show_tree(show_agent, show_var, resolve, e)
+                    )
+                    .unwrap();
                     for subtree in i {
-                        write!(&mut s, " {}", self.show_tree(show_agent, scope, subtree)).unwrap();
+                        write!(
+                            &mut s,
+                            " {}",
+                            self.show_tree(show_agent, show_var, resolve, subtree)
+                        )
+                        .unwrap();
                     }
                     write!(&mut s, ")").unwrap();
                 }
                 s
             }
             Tree::Var { id } => {
-                if let Some(Some(b)) = self.vars.get(*id) {
-                    self.show_tree(show_agent, scope, b)
+                if resolve && let Some(Some(b)) = self.vars.get(*id) {
+                    self.show_tree(show_agent, show_var, resolve, b)
                 } else {
-                    let l = scope.len();
-                    scope
-                        .entry(*id)
-                        .or_insert_with(|| format!("x{}", l))
-                        .clone()
+                    show_var(*id)
                 }
             }
         }
     }
+    /// Copies `tree` with bound variables replaced by what they're bound to.
+    /// A variable whose binding leads back to itself is left as it is, so
+    /// this terminates even on nets that [`Net::validate`] would reject.
     pub fn substitute_ref(&self, tree: &Tree) -> Tree {
+        self.substitute_ref_in(tree, &mut BTreeSet::new())
+    }
+    fn substitute_ref_in(&self, tree: &Tree, visiting: &mut BTreeSet<VarId>) -> Tree {
         match tree {
             Tree::Agent { id, aux } => Tree::Agent {
                 id: *id,
-                aux: aux.into_iter().map(|x| self.substitute_ref(x)).collect(),
+                aux: aux
+                    .iter()
+                    .map(|x| self.substitute_ref_in(x, visiting))
+                    .collect(),
             },
             Tree::Var { id } => {
-                if let Some(Some(b)) = self.vars.get(*id) {
-                    self.substitute_ref(b)
+                if let Some(Some(b)) = self.vars.get(*id)
+                    && visiting.insert(*id)
+                {
+                    let b = self.substitute_ref_in(b, visiting);
+                    visiting.remove(id);
+                    b
                 } else {
                     Tree::Var { id: *id }
                 }
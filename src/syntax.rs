@@ -1,29 +1,166 @@
+use std::collections::BTreeMap;
+
 use TSPL::Parser;
 
+/// Byte offsets of some text in the parsed source, end excluded. Trees that
+/// weren't parsed, or were parsed from some other text, have an empty span.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, Clone)]
 pub enum Tree {
     Agent {
         name: String,
         aux: Vec<Tree>,
+        /// The whole occurrence, ports included.
+        span: Span,
     },
     Variable {
         name: String,
     },
+    /// A numbered port, `#n`. Within a statement, all occurrences of the same
+    /// number are wired together, just like a variable.
+    Port(usize),
     With {
         rest: Box<Tree>,
         redex: Box<(Tree, Tree)>,
     },
 }
 
+impl Tree {
+    /// Calls `f` on this tree and every subtree, including `with` redexes.
+    pub fn visit(&self, f: &mut impl FnMut(&Tree)) {
+        f(self);
+        match self {
+            Tree::Agent { aux, .. } => aux.iter().for_each(|x| x.visit(f)),
+            Tree::Variable { .. } | Tree::Port(_) => (),
+            Tree::With { rest, redex } => {
+                rest.visit(f);
+                redex.0.visit(f);
+                redex.1.visit(f);
+            }
+        }
+    }
+    /// Name under which a variable or numbered port is scoped.
+    pub fn var_name(&self) -> Option<String> {
+        match self {
+            Tree::Variable { name } => Some(name.clone()),
+            Tree::Port(n) => Some(format!("#{}", n)),
+            _ => None,
+        }
+    }
+    pub fn count_vars(&self, counts: &mut BTreeMap<String, usize>) {
+        self.visit(&mut |t| {
+            if let Some(name) = t.var_name() {
+                *counts.entry(name).or_default() += 1;
+            }
+        })
+    }
+    /// Replaces every agent named `name` that has no ports with `with`.
+    pub fn replace_agent(&self, name: &str, with: &Tree) -> Tree {
+        match self {
+            Tree::Agent {
+                name: agent, aux, ..
+            } if agent == name && aux.is_empty() => with.clone(),
+            Tree::Agent {
+                name: agent,
+                aux,
+                span,
+            } => Tree::Agent {
+                name: agent.clone(),
+                aux: aux.iter().map(|x| x.replace_agent(name, with)).collect(),
+                span: *span,
+            },
+            Tree::Variable { .. } | Tree::Port(_) => self.clone(),
+            Tree::With { rest, redex } => Tree::With {
+                rest: Box::new(rest.replace_agent(name, with)),
+                redex: Box::new((
+                    redex.0.replace_agent(name, with),
+                    redex.1.replace_agent(name, with),
+                )),
+            },
+        }
+    }
+    /// Replaces every occurrence of the variable `name` with `with`.
+    pub fn replace_var(&self, name: &str, with: &Tree) -> Tree {
+        match self {
+            Tree::Variable { name: n } if n == name => with.clone(),
+            Tree::Agent {
+                name: agent,
+                aux,
+                span,
+            } => Tree::Agent {
+                name: agent.clone(),
+                aux: aux.iter().map(|x| x.replace_var(name, with)).collect(),
+                span: *span,
+            },
+            Tree::Variable { .. } | Tree::Port(_) => self.clone(),
+            Tree::With { rest, redex } => Tree::With {
+                rest: Box::new(rest.replace_var(name, with)),
+                redex: Box::new((
+                    redex.0.replace_var(name, with),
+                    redex.1.replace_var(name, with),
+                )),
+            },
+        }
+    }
+}
+
+/// A parse error along with where parsing stopped. Lines and columns start at 1,
+/// and columns count characters.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    /// Writes the error as a JSON object with `line`, `col` and `message` fields.
+    pub fn to_json(&self) -> String {
+        let mut message = String::new();
+        for c in self.message.chars() {
+            match c {
+                '"' => message.push_str("\\\""),
+                '\\' => message.push_str("\\\\"),
+                '\n' => message.push_str("\\n"),
+                '\t' => message.push_str("\\t"),
+                c if c.is_control() => message.push_str(&format!("\\u{:04x}", c as u32)),
+                c => message.push(c),
+            }
+        }
+        format!(
+            "{{\"line\": {}, \"col\": {}, \"message\": \"{}\"}}",
+            self.line, self.col, message
+        )
+    }
+}
+
+/// A definition read by [`CodeParser::parse_hvm`]. `root` is the tree
+/// connected to the outside of the net.
+#[derive(Debug, Clone)]
+pub struct HvmDefinition {
+    pub name: String,
+    pub root: Tree,
+    pub net: Net,
+}
+
 #[derive(Debug, Clone)]
 pub struct TypedMatch {
     pub name: String,
     pub aux: Vec<(Tree, Tree, Tree)>,
+    /// The whole match, ports included.
+    pub span: Span,
 }
 #[derive(Debug, Clone)]
 pub struct UntypedMatch {
     pub name: String,
     pub aux: Vec<Tree>,
+    /// The whole match, ports included.
+    pub span: Span,
 }
 #[derive(Debug, Clone)]
 pub struct Net {
@@ -32,14 +169,221 @@ pub struct Net {
 
 #[derive(Debug, Clone)]
 pub enum Statement {
-    Decl(TypedMatch, Vec<Tree>, UntypedMatch),
-    Def(UntypedMatch, UntypedMatch),
-    Check(bool, Net),
+    /// A declaration, followed by the type parameters of its `forall` clause, if any.
+    Decl(TypedMatch, Vec<Tree>, UntypedMatch, Vec<String>),
+    /// A rule, followed by the bindings of its `where` clause, if any.
+    Def(UntypedMatch, UntypedMatch, Vec<(String, Tree)>),
+    /// A check, with the name it was given, if any.
+    Check(bool, Option<String>, Net),
+    Agent(String, usize),
+}
+
+impl Statement {
+    /// Calls `f` with the name and arity of every agent occurrence in the statement.
+    pub fn for_each_agent(&self, f: &mut impl FnMut(&str, usize)) {
+        let mut trees = vec![];
+        match self {
+            Statement::Decl(a, vars, t, _) => {
+                f(&a.name, a.aux.len());
+                for (from, to, r#type) in &a.aux {
+                    trees.extend([from.clone(), to.clone(), r#type.clone()]);
+                }
+                trees.extend(vars.iter().cloned());
+                trees.push(t.clone().into());
+            }
+            Statement::Def(a, b, bindings) => {
+                trees.push(a.clone().into());
+                trees.push(b.clone().into());
+                trees.extend(bindings.iter().map(|x| x.1.clone()));
+            }
+            Statement::Check(_, _, net) => {
+                for (a, b) in &net.interactions {
+                    trees.extend([a.clone(), b.clone()]);
+                }
+            }
+            Statement::Agent(..) => (),
+        }
+        for tree in &trees {
+            tree.visit(&mut |t| {
+                if let Tree::Agent { name, aux, .. } = t {
+                    f(name, aux.len())
+                }
+            })
+        }
+    }
+}
+
+/// Writes `text` between two `delim` characters, escaped so that
+/// `CodeParser::parse_quoted` reads it back.
+fn write_quoted(f: &mut std::fmt::Formatter<'_>, text: &str, delim: char) -> std::fmt::Result {
+    write!(f, "{}", delim)?;
+    for c in text.chars() {
+        match c {
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\\' => write!(f, "\\\\")?,
+            c if c == delim => write!(f, "\\{}", c)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "{}", delim)
+}
+
+/// Writes a name, in backticks if it wouldn't read back as the same name otherwise.
+fn write_name(f: &mut std::fmt::Formatter<'_>, name: &str) -> std::fmt::Result {
+    let plain = !name.is_empty()
+        && name.chars().all(CodeParser::is_name_char)
+        && !name.starts_with(['`', '#'])
+        && !name.contains(['→', '"'])
+        && !name.contains("->");
+    if plain {
+        write!(f, "{}", name)
+    } else {
+        write_quoted(f, name, '`')
+    }
+}
+
+fn write_agent(f: &mut std::fmt::Formatter<'_>, name: &str, aux: &[Tree]) -> std::fmt::Result {
+    write_name(f, name)?;
+    if !aux.is_empty() {
+        write!(
+            f,
+            "({})",
+            aux.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the tree back as source. Trees read by the parser read back the same.
+impl std::fmt::Display for Tree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tree::Agent { name, aux, .. } => write_agent(f, name, aux),
+            Tree::Variable { name } => write_name(f, name),
+            Tree::Port(n) => write!(f, "#{}", n),
+            Tree::With { rest, redex } => write!(f, "{} with {} ~ {}", rest, redex.0, redex.1),
+        }
+    }
+}
+
+impl std::fmt::Display for UntypedMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_agent(f, &self.name, &self.aux)
+    }
+}
+
+impl std::fmt::Display for TypedMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_name(f, &self.name)?;
+        if !self.aux.is_empty() {
+            let ports: Vec<String> = self
+                .aux
+                .iter()
+                .map(|(from, to, r#type)| format!("{} -> {}: {}", from, to, r#type))
+                .collect();
+            write!(f, "({})", ports.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Statement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Statement::Decl(agent, intermediate, r#type, params) => {
+                write!(f, "{}", agent)?;
+                for tree in intermediate {
+                    write!(f, ": {}", tree)?;
+                }
+                write!(f, ": {}", r#type)?;
+                if !params.is_empty() {
+                    write!(f, " forall {{ {} }}", params.join(" "))?;
+                }
+                Ok(())
+            }
+            Statement::Def(a, b, bindings) => {
+                write!(f, "{} ~ {}", a, b)?;
+                if !bindings.is_empty() {
+                    writeln!(f, " where {{")?;
+                    for (name, tree) in bindings {
+                        write!(f, "    ")?;
+                        write_name(f, name)?;
+                        writeln!(f, " = {}", tree)?;
+                    }
+                    write!(f, "}}")?;
+                }
+                Ok(())
+            }
+            Statement::Check(positive, name, net) => {
+                write!(f, "check {}", if *positive { "yes" } else { "no" })?;
+                let (a, b) = &net.interactions[0];
+                if let Some(name) = name {
+                    write!(f, " ")?;
+                    write_quoted(f, name, '"')?;
+                    write!(f, " {{ {} ~ {} }}", a, b)
+                } else {
+                    write!(f, " {} ~ {}", a, b)
+                }
+            }
+            Statement::Agent(name, arity) => {
+                write!(f, "agent ")?;
+                write_name(f, name)?;
+                write!(f, "/{}", arity)
+            }
+        }
+    }
+}
+
+/// Writes a whole program, one statement per line. Declarations, checks and
+/// `agent` statements start a new paragraph unless they follow one of their
+/// own kind, so rules stay next to the declaration before them. `before[i]`
+/// goes on lines of its own before statement `i`, and starts a new paragraph
+/// too, and `trailing[i]` goes after it on the same line. The last entry of
+/// `before` goes at the end.
+fn format_book(book: &[Statement], before: &[Vec<&str>], trailing: &[Option<&str>]) -> String {
+    let mut out = String::new();
+    for (i, statement) in book.iter().enumerate() {
+        let kind = std::mem::discriminant(statement);
+        let groups = !matches!(statement, Statement::Def(..));
+        let new_kind = groups && std::mem::discriminant(&book[i.saturating_sub(1)]) != kind;
+        if i > 0 && (new_kind || !before[i].is_empty()) {
+            out.push('\n');
+        }
+        for comment in &before[i] {
+            out.push_str(&format!("{}\n", comment));
+        }
+        match trailing[i] {
+            Some(comment) => out.push_str(&format!("{} {}\n", statement, comment)),
+            None => out.push_str(&format!("{}\n", statement)),
+        }
+    }
+    for comment in &before[book.len()] {
+        out.push_str(&format!("{}\n", comment));
+    }
+    out
+}
+
+impl From<UntypedMatch> for Tree {
+    fn from(value: UntypedMatch) -> Self {
+        Tree::Agent {
+            name: value.name,
+            aux: value.aux,
+            span: value.span,
+        }
+    }
 }
 
 pub struct CodeParser<'i> {
     input: &'i str,
     index: usize,
+    /// Every comment skipped so far, from its `;` to the end of its line.
+    comments: Vec<Span>,
+    /// Where each statement read by `parse_book` starts.
+    starts: Vec<usize>,
 }
 impl<'i> Parser<'i> for CodeParser<'i> {
     fn input(&mut self) -> &'i str {
@@ -51,7 +395,12 @@ impl<'i> Parser<'i> for CodeParser<'i> {
 }
 impl<'i> CodeParser<'i> {
     pub fn new(input: &'i str) -> Self {
-        Self { input, index: 0 }
+        Self {
+            input,
+            index: 0,
+            comments: vec![],
+            starts: vec![],
+        }
     }
 }
 
@@ -63,6 +412,7 @@ impl<'i> CodeParser<'i> {
                 continue;
             }
             if c == ';' {
+                let start = self.index;
                 while let Some(c) = self.peek_one() {
                     if c != '\n' {
                         self.advance_one();
@@ -70,6 +420,11 @@ impl<'i> CodeParser<'i> {
                         break;
                     }
                 }
+                let end = start + self.input[start..self.index].trim_end().len();
+                // After backtracking, the same comment can be skipped twice.
+                if self.comments.last().is_none_or(|x| x.start < start) {
+                    self.comments.push(Span { start, end });
+                }
                 self.advance_one(); // Skip the newline character as well
                 continue;
             }
@@ -88,8 +443,38 @@ impl<'i> CodeParser<'i> {
                 "no" => false,
                 _ => return Err("Expected yes or no".to_string()),
             };
+            self.skip_trivia();
+            // Named checks have their net in braces.
+            if self.peek_one() == Some('"') {
+                let name = self.parse_quoted('"')?;
+                self.skip_trivia();
+                self.consume("{")?;
+                let net = self.parse_net()?;
+                self.skip_trivia();
+                self.consume("}")?;
+                return Ok(Statement::Check(positive, Some(name), net));
+            }
             let net = self.parse_net()?;
-            return Ok(Statement::Check(positive, net));
+            return Ok(Statement::Check(positive, None, net));
+        }
+        if self.peek_many(5) == Some("agent") {
+            self.consume("agent")?;
+            self.skip_trivia();
+            let quoted = self.peek_one() == Some('`');
+            let name = self.parse_name()?;
+            let (name, arity) = if quoted {
+                self.consume("/")?;
+                (name, self.take_while(|c| c.is_ascii_digit()).to_owned())
+            } else {
+                let Some((name, arity)) = name.rsplit_once('/') else {
+                    return self.expected("agent name followed by `/` and its arity");
+                };
+                (name.to_owned(), arity.to_owned())
+            };
+            let Ok(arity) = arity.parse() else {
+                return self.expected("arity");
+            };
+            return Ok(Statement::Agent(name, arity));
         }
         let untyped_match = self.parse_untyped_match();
         self.skip_trivia();
@@ -98,10 +483,14 @@ impl<'i> CodeParser<'i> {
         {
             self.consume("~")?;
             let a = self.parse_untyped_match()?;
-            return Ok(Statement::Def(untyped_match, a));
+            let bindings = self.parse_where()?;
+            return Ok(Statement::Def(untyped_match, a, bindings));
         }
         self.index = index;
-        let typed_match = self.parse_typed_match();
+        let typed_match = match self.parse_typed_match() {
+            Err((e, true)) => return Err(e),
+            x => x.map_err(|x| x.0),
+        };
         self.skip_trivia();
         if let Ok(typed_match) = typed_match.clone()
             && self.peek_one() == Some(':')
@@ -124,22 +513,175 @@ impl<'i> CodeParser<'i> {
             }
             self.index = index;
             let end = self.parse_untyped_match()?;
-            return Ok(Statement::Decl(typed_match, vars, end));
+            let params = self.parse_forall()?;
+            return Ok(Statement::Decl(typed_match, vars, end, params));
         }
         self.index = index;
         self.expected("Expected typed pattern match or untyped pattern match.")?
     }
+    /// Parses an optional `where { x = tree ... }` clause.
+    fn parse_where(&mut self) -> Result<Vec<(String, Tree)>, String> {
+        self.skip_trivia();
+        let mut bindings = vec![];
+        if self.peek_many(5) != Some("where") {
+            return Ok(bindings);
+        }
+        self.consume("where")?;
+        self.skip_trivia();
+        self.consume("{")?;
+        self.skip_trivia();
+        while self.peek_one() != Some('}') {
+            let name = self.parse_var()?;
+            self.skip_trivia();
+            self.consume("=")?;
+            bindings.push((name, self.parse_tree()?));
+            self.skip_trivia();
+        }
+        self.consume("}")?;
+        Ok(bindings)
+    }
+    /// Parses an optional `forall { a b ... }` clause.
+    fn parse_forall(&mut self) -> Result<Vec<String>, String> {
+        self.skip_trivia();
+        let mut params = vec![];
+        if self.peek_many(6) != Some("forall") {
+            return Ok(params);
+        }
+        self.consume("forall")?;
+        self.skip_trivia();
+        self.consume("{")?;
+        self.skip_trivia();
+        while self.peek_one() != Some('}') {
+            params.push(self.parse_var()?);
+            self.skip_trivia();
+        }
+        self.consume("}")?;
+        Ok(params)
+    }
+    /// Line and column of the current position, both starting at 1.
+    pub fn position(&self) -> (usize, usize) {
+        let before = &self.input[..self.index];
+        let line = before.matches('\n').count() + 1;
+        let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        (line, col)
+    }
+    /// Like `parse_book`, but reports where parsing stopped, with the message
+    /// stripped of the highlighted source.
+    pub fn parse_book_located(&mut self) -> Result<Vec<Statement>, ParseError> {
+        self.parse_book().map_err(|e| {
+            let (line, col) = self.position();
+            let message = e
+                .strip_prefix("\x1b[1mPARSE_ERROR\n- expected: \x1b[0m")
+                .and_then(|x| x.split_once("\x1b[1m\n- detected"))
+                .map(|(x, _)| {
+                    if x.starts_with("Expected") {
+                        x.to_string()
+                    } else {
+                        format!("expected {}", x)
+                    }
+                })
+                .unwrap_or(e);
+            ParseError { line, col, message }
+        })
+    }
     pub fn parse_book(&mut self) -> Result<Vec<Statement>, String> {
         self.skip_trivia();
         let mut book = vec![];
         while self.peek_one().is_some() {
+            self.starts.push(self.index);
             book.push(self.parse_statement()?);
             self.skip_trivia();
         }
         Ok(book)
     }
+    /// Writes `book`, which `parse_book` just read, back out in a canonical
+    /// layout, keeping the comments of the source. Whole-line
+    /// comments and comments at the end of a statement stay where they are;
+    /// comments inside a statement move to the line before it. Reading the
+    /// result back has to give the same statements and comments, or this
+    /// fails instead. Statements are compared by how they print, which leaves
+    /// out their spans.
+    pub fn format(&self, book: &[Statement]) -> Result<String, String> {
+        let input = self.input;
+        // Where each statement ends, before the trivia after it.
+        let ends: Vec<usize> = (0..book.len())
+            .map(|i| {
+                let mut end = self.starts.get(i + 1).copied().unwrap_or(input.len());
+                loop {
+                    end = input[..end].trim_end().len();
+                    match self.comments.iter().find(|x| x.end == end) {
+                        Some(comment) => end = comment.start,
+                        None => break end,
+                    }
+                }
+            })
+            .collect();
+        let mut before = vec![vec![]; book.len() + 1];
+        let mut trailing = vec![None; book.len()];
+        for comment in &self.comments {
+            let text = &input[comment.start..comment.end];
+            let i = self.starts.partition_point(|&x| x <= comment.start);
+            if i == 0 {
+                before[0].push(text);
+            } else if comment.start < ends[i - 1] {
+                before[i - 1].push(text);
+            } else if !input[ends[i - 1]..comment.start].contains('\n') {
+                trailing[i - 1] = Some(text);
+            } else {
+                before[i].push(text);
+            }
+        }
+        let out = format_book(book, &before, &trailing);
+
+        let mut parser = CodeParser::new(&out);
+        let again = parser
+            .parse_book()
+            .map_err(|e| format!("The formatted program doesn't parse:\n{}", e))?;
+        for (a, b) in book.iter().zip(&again) {
+            if a.to_string() != b.to_string() {
+                return Err(format!("Formatting would change {} into {}", a, b));
+            }
+        }
+        if book.len() != again.len() || self.comments.len() != parser.comments.len() {
+            return Err("Formatting would lose statements or comments".to_string());
+        }
+        Ok(out)
+    }
+    /// Like `parse_book`, but when a statement fails to parse, skips to the
+    /// next line that starts a statement and carries on. Returns every
+    /// statement that parsed, along with the errors for the ones that didn't.
+    pub fn parse_book_lenient(&mut self) -> (Vec<Statement>, Vec<String>) {
+        self.skip_trivia();
+        let mut book = vec![];
+        let mut errors = vec![];
+        while self.peek_one().is_some() {
+            let index = self.index;
+            match self.parse_statement() {
+                Ok(statement) => book.push(statement),
+                Err(e) => {
+                    errors.push(e);
+                    self.index = index;
+                    while let Some(c) = self.advance_one()
+                        && !(c == '\n' && self.at_statement_start())
+                    {}
+                }
+            }
+            self.skip_trivia();
+        }
+        (book, errors)
+    }
+    /// Whether a statement could start here: the line starts with an agent
+    /// name or a keyword, without indentation. Continuation lines of a
+    /// statement are usually indented or start with a port or a brace.
+    fn at_statement_start(&self) -> bool {
+        let rest = &self.input[self.index..];
+        rest.starts_with("check ")
+            || rest.starts_with("agent ")
+            || rest.starts_with(|c: char| c.is_uppercase() || c == '!' || c == '`')
+    }
     fn is_name_char(c: char) -> bool {
-        return !c.is_whitespace() && !c.is_control() && !":=~()".contains(c);
+        // `;` starts a comment, even right after a name.
+        return !c.is_whitespace() && !c.is_control() && !":=~(){};".contains(c);
     }
     fn parse_var(&mut self) -> Result<String, String> {
         self.skip_trivia();
@@ -151,6 +693,14 @@ impl<'i> CodeParser<'i> {
     }
     fn parse_name(&mut self) -> Result<String, String> {
         self.skip_trivia();
+        if self.peek_one() == Some('`') {
+            let name = self.parse_quoted('`')?;
+            return if name.is_empty() {
+                self.expected("name")
+            } else {
+                Ok(name)
+            };
+        }
         let name = self.take_while(|c| Self::is_name_char(c));
         if name.is_empty() {
             self.expected("name")
@@ -158,9 +708,38 @@ impl<'i> CodeParser<'i> {
             Ok(name.to_owned())
         }
     }
+    /// Parses text between two `delim` characters. Inside, `\\`, `\n`, `\t`
+    /// and a backslash followed by the delimiter or `"` are escapes; any other
+    /// character stands for itself. Shared by everything that reads quoted text.
+    fn parse_quoted(&mut self, delim: char) -> Result<String, String> {
+        self.consume(&delim.to_string())?;
+        let mut out = String::new();
+        loop {
+            match self.advance_one() {
+                None => return self.expected(&format!("closing {}", delim)),
+                Some(c) if c == delim => return Ok(out),
+                Some('\\') => match self.advance_one() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some(c) if c == '\\' || c == '"' || c == delim => out.push(c),
+                    _ => return self.expected("escape sequence"),
+                },
+                Some(c) => out.push(c),
+            }
+        }
+    }
+    /// Span from `start` to the current position.
+    fn span_from(&self, start: usize) -> Span {
+        Span {
+            start,
+            end: self.index,
+        }
+    }
     fn parse_untyped_match(&mut self) -> Result<UntypedMatch, String> {
         self.skip_trivia();
+        let start = self.index;
         let name = self.parse_name()?;
+        let mut span = self.span_from(start);
         self.skip_trivia();
         let args = if self.peek_one() == Some('(') {
             self.consume("(")?;
@@ -171,42 +750,89 @@ impl<'i> CodeParser<'i> {
                 self.skip_trivia();
             }
             self.consume(")")?;
+            span = self.span_from(start);
             args
         } else {
             vec![]
         };
-        Ok(UntypedMatch { name, aux: args })
+        Ok(UntypedMatch {
+            name,
+            aux: args,
+            span,
+        })
     }
-    fn parse_typed_match(&mut self) -> Result<TypedMatch, String> {
+    /// On failure, also tells whether it happened within the ports, where
+    /// the error says which part of which port is malformed.
+    fn parse_typed_match(&mut self) -> Result<TypedMatch, (String, bool)> {
         self.skip_trivia();
-        let name = self.parse_name()?;
+        let start = self.index;
+        let name = self.parse_name().map_err(|e| (e, false))?;
+        let mut span = self.span_from(start);
         self.skip_trivia();
         let args = if self.peek_one() == Some('(') {
-            self.consume("(")?;
+            self.advance_one();
             let mut args = vec![];
             self.skip_trivia();
             while self.peek_one() != Some(')') {
-                let from = self.parse_tree()?;
-                self.skip_trivia();
-                self.consume("->")?;
-                let to = self.parse_tree()?;
-                self.skip_trivia();
-                self.consume(":")?;
-                let r#type = self.parse_tree()?;
-                args.push((from, to, r#type));
+                let port = args.len() + 1;
+                args.push(self.parse_typed_port(&name, port).map_err(|e| (e, true))?);
                 self.skip_trivia();
             }
-            self.consume(")")?;
+            self.advance_one();
+            span = self.span_from(start);
             args
         } else {
             vec![]
         };
-        Ok(TypedMatch { name, aux: args })
+        Ok(TypedMatch {
+            name,
+            aux: args,
+            span,
+        })
+    }
+    /// Parses `from -> to : type`, the `port`th port of the agent `name`.
+    fn parse_typed_port(&mut self, name: &str, port: usize) -> Result<(Tree, Tree, Tree), String> {
+        let within = format!("port {} of {}", port, name);
+        if self.peek_one().is_none() {
+            return self.expected(&format!("`)` or {}", within));
+        }
+        let Ok(from) = self.parse_tree() else {
+            return self.expected(&format!("tree at the start of {}", within));
+        };
+        self.skip_trivia();
+        if self.peek_one() == Some('→') {
+            self.advance_one();
+        } else if self.peek_many(2) == Some("->") {
+            self.advance_many(2);
+        } else {
+            return self.expected(&format!("`->` after the first tree of {}", within));
+        }
+        let Ok(to) = self.parse_tree() else {
+            return self.expected(&format!("tree after `->` in {}", within));
+        };
+        self.skip_trivia();
+        if self.peek_one() != Some(':') {
+            return self.expected(&format!("`:` after the second tree of {}", within));
+        }
+        self.advance_one();
+        let Ok(r#type) = self.parse_tree() else {
+            return self.expected(&format!("type after `:` in {}", within));
+        };
+        Ok((from, to, r#type))
     }
     fn parse_tree(&mut self) -> Result<Tree, String> {
         self.skip_trivia();
+        let start = self.index;
+        let quoted = self.peek_one() == Some('`');
         let name = self.parse_name()?;
-        let res = if name.chars().next().unwrap().is_lowercase() {
+        let mut span = self.span_from(start);
+        let port = name.strip_prefix('#').and_then(|x| x.parse().ok());
+        let res = if let Some(port) = port
+            && !quoted
+        {
+            // Numbered port
+            Tree::Port(port)
+        } else if name.chars().next().unwrap().is_lowercase() {
             // Variable
             Tree::Variable { name }
         } else {
@@ -221,11 +847,16 @@ impl<'i> CodeParser<'i> {
                     self.skip_trivia();
                 }
                 self.consume(")")?;
+                span = self.span_from(start);
                 args
             } else {
                 vec![]
             };
-            Tree::Agent { name, aux: args }
+            Tree::Agent {
+                name,
+                aux: args,
+                span,
+            }
         };
         self.skip_trivia();
         if self.peek_many(4) == Some("with") {
@@ -251,4 +882,145 @@ impl<'i> CodeParser<'i> {
             interactions: vec![(a, b)],
         })
     }
+    /// Parses definitions written in an HVM-like notation, `@name = root & a ~ b & ...`,
+    /// like the ones written by the `combinators` module. `*`, `(a b)` and
+    /// `{a b}` become the agents named `eraser`, `constructor` and
+    /// `duplicator`, and variables keep their names.
+    pub fn parse_hvm(
+        &mut self,
+        eraser: &str,
+        constructor: &str,
+        duplicator: &str,
+    ) -> Result<Vec<HvmDefinition>, String> {
+        let names = [eraser, constructor, duplicator];
+        let mut book = vec![];
+        self.skip_trivia();
+        while self.peek_one().is_some() {
+            self.consume("@")?;
+            let name = self.parse_name()?;
+            self.skip_trivia();
+            self.consume("=")?;
+            let root = self.parse_hvm_tree(names)?;
+            let mut net = Net {
+                interactions: vec![],
+            };
+            self.skip_trivia();
+            while self.peek_one() == Some('&') {
+                self.consume("&")?;
+                let a = self.parse_hvm_tree(names)?;
+                self.skip_trivia();
+                self.consume("~")?;
+                let b = self.parse_hvm_tree(names)?;
+                net.interactions.push((a, b));
+                self.skip_trivia();
+            }
+            book.push(HvmDefinition { name, root, net });
+        }
+        Ok(book)
+    }
+    fn parse_hvm_tree(
+        &mut self,
+        [eraser, constructor, duplicator]: [&str; 3],
+    ) -> Result<Tree, String> {
+        self.skip_trivia();
+        let start = self.index;
+        let (name, close) = match self.peek_one() {
+            Some('*') => {
+                self.consume("*")?;
+                return Ok(Tree::Agent {
+                    name: eraser.to_string(),
+                    aux: vec![],
+                    span: self.span_from(start),
+                });
+            }
+            Some('(') => (constructor, ")"),
+            Some('{') => (duplicator, "}"),
+            Some('@') => return self.expected("tree; references aren't supported"),
+            _ => {
+                let name = self.take_while(|c| Self::is_name_char(c) && c != '&');
+                if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+                    return self.expected("tree; numbers aren't supported");
+                }
+                return Ok(Tree::Variable {
+                    name: name.to_string(),
+                });
+            }
+        };
+        self.advance_one();
+        let a = self.parse_hvm_tree([eraser, constructor, duplicator])?;
+        let b = self.parse_hvm_tree([eraser, constructor, duplicator])?;
+        self.skip_trivia();
+        self.consume(close)?;
+        Ok(Tree::Agent {
+            name: name.to_string(),
+            aux: vec![a, b],
+            span: self.span_from(start),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `src` and prints each statement, which leaves out spans.
+    fn parse(src: &str) -> Vec<String> {
+        let book = CodeParser::new(src).parse_book().unwrap();
+        book.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn format(src: &str) -> String {
+        let mut parser = CodeParser::new(src);
+        let book = parser.parse_book().unwrap();
+        parser.format(&book).unwrap()
+    }
+
+    #[test]
+    fn format_round_trips() {
+        for src in [include_str!("../test.itt"), include_str!("prelude.itt")] {
+            let formatted = format(src);
+            assert_eq!(parse(&formatted), parse(src));
+            assert_eq!(format(&formatted), formatted);
+        }
+    }
+
+    #[test]
+    fn comment_after_name() {
+        assert_eq!(
+            parse("Not;comment\n(False) ~ True"),
+            parse("Not(False) ~ True")
+        );
+        assert_eq!(
+            parse("check yes True ~ Not;comment\n(x)"),
+            parse("check yes True ~ Not(x)")
+        );
+    }
+
+    #[test]
+    fn comment_between_name_and_ports() {
+        assert_eq!(
+            parse("Not ; negation\n(x -> x: Bool): !Bool"),
+            parse("Not(x -> x: Bool): !Bool")
+        );
+        assert_eq!(
+            parse("Foo ; comment\n(a b) ~ Bar ; another\n(a b)"),
+            parse("Foo(a b) ~ Bar(a b)")
+        );
+    }
+
+    #[test]
+    fn names_next_to_braces() {
+        assert_eq!(
+            parse("Nil: List(a) forall {a}"),
+            parse("Nil: List(a) forall { a }")
+        );
+        assert_eq!(
+            parse("Two ~ Succ(one) where {one = Succ(Zero)}"),
+            parse("Two ~ Succ(one) where { one = Succ(Zero) }")
+        );
+        assert_eq!(
+            parse("check yes \"named\" {True ~ Not}"),
+            parse("check yes \"named\" { True ~ Not }")
+        );
+    }
 }